@@ -0,0 +1,408 @@
+use std::{
+    env,
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::Utc;
+use serde::Deserialize;
+use sha2::{digest::DynDigest, Digest, Sha256, Sha384, Sha512};
+use tokio::{
+    fs::{self, OpenOptions},
+    io::AsyncWriteExt,
+};
+
+use crate::{metadata_cache::MetadataCache, npmrc::Npmrc};
+
+/// The prefix of the index keys npm stores fetched tarballs under.
+const REQUEST_CACHE_KEY_PREFIX: &str = "make-fetch-happen:request-cache:";
+
+/// A content-addressable cache laid out like npm's `_cacache`, holding tarballs by integrity
+/// and indexing them by the URL they were fetched from. Used both to read npm's own cache
+/// and for the tarballs fpm keeps itself with `keep-tarballs`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Cacache {
+    /// The cache is disabled without a directory.
+    pub dir: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+struct IndexEntry {
+    key: String,
+    integrity: Option<String>,
+}
+
+impl Cacache {
+    /// `_cacache` in npm's `cache` directory, `~/.npm/_cacache` by default.
+    pub fn npm_cache(npmrc: &Npmrc) -> Self {
+        let cache = npmrc.get("cache").map(PathBuf::from).or_else(|| {
+            env::var_os("LOCALAPPDATA")
+                .map(|local_app_data| PathBuf::from(local_app_data).join("npm-cache"))
+                .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".npm")))
+        });
+
+        Self {
+            dir: cache.map(|cache| cache.join("_cacache")),
+        }
+    }
+
+    /// The `_cacache` in `cache-dir` that downloaded tarballs are kept in when `keep` is set.
+    pub fn kept_tarballs(metadata_cache: &MetadataCache, keep: bool) -> Self {
+        Self {
+            dir: metadata_cache
+                .dir
+                .as_ref()
+                .filter(|_| keep)
+                .map(|dir| dir.join("_cacache")),
+        }
+    }
+
+    /// Find the tarball with `integrity`, or the one fetched from `url` if the integrity
+    /// isn't known or has no sha2 hash, like the lone `sha1-` of old packages.
+    /// Returns where its content is, and the integrity to check it against
+    /// while reading it, since the cache may be corrupt.
+    pub async fn find_tarball(
+        &self,
+        integrity: Option<&str>,
+        url: &str,
+    ) -> Option<(PathBuf, String)> {
+        let dir = self.dir.as_ref()?;
+        let integrity = match integrity.filter(|integrity| strongest_hash(integrity).is_some()) {
+            Some(integrity) => integrity.to_string(),
+            None => self.find_integrity(url).await?,
+        };
+
+        let (algorithm, digest) = strongest_hash(&integrity)?;
+        let path = content_path(dir, algorithm, &digest);
+        fs::try_exists(&path)
+            .await
+            .ok()?
+            .then_some((path, integrity))
+    }
+
+    /// Copy the tarball at `tarball`, fetched from `url`, to the cache under its `integrity`,
+    /// and index it by `url` so that it's found without knowing the integrity.
+    /// Index entries are hashed with sha256 rather than npm's sha1, so only fpm reads them back,
+    /// while npm still finds the content by its integrity.
+    pub async fn write_tarball(
+        &self,
+        tarball: &Path,
+        integrity: &str,
+        url: &str,
+    ) -> anyhow::Result<()> {
+        let Some(dir) = &self.dir else {
+            return Ok(());
+        };
+        let Some((algorithm, digest)) = strongest_hash(integrity) else {
+            return Ok(());
+        };
+
+        if self.find_integrity(url).await.as_deref() != Some(integrity) {
+            let key = format!("{REQUEST_CACHE_KEY_PREFIX}{url}");
+            let entry = serde_json::json!({
+                "key": key,
+                "integrity": integrity,
+                "time": Utc::now().timestamp_millis(),
+                "size": fs::metadata(tarball).await?.len(),
+            })
+            .to_string();
+
+            let bucket_path = bucket_path(dir, &key);
+            if let Some(parent) = bucket_path.parent() {
+                fs::create_dir_all(parent).await?;
+            }
+            let mut bucket = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(bucket_path)
+                .await?;
+            let line = format!("\n{}\t{entry}", to_hex(&Sha256::digest(entry.as_bytes())));
+            bucket.write_all(line.as_bytes()).await?;
+        }
+
+        let path = content_path(dir, algorithm, &digest);
+        if fs::try_exists(&path).await? {
+            return Ok(());
+        }
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+
+        let temp_path = path.with_extension(format!("{}", rand::random::<u32>()));
+        fs::copy(tarball, &temp_path).await?;
+
+        if let Err(error) = fs::rename(&temp_path, &path).await {
+            let _ = fs::remove_file(&temp_path).await;
+            return Err(error.into());
+        }
+
+        Ok(())
+    }
+
+    /// Find the integrity of the response npm cached for `url` in the index buckets.
+    async fn find_integrity(&self, url: &str) -> Option<String> {
+        let key = format!("{REQUEST_CACHE_KEY_PREFIX}{url}");
+        let bucket_path = bucket_path(self.dir.as_ref()?, &key);
+
+        // Each line is `<sha1 of entry>\t<entry>`, and entries are only ever appended,
+        // so the last one for the key wins. A `null` integrity marks a deleted entry.
+        fs::read_to_string(bucket_path)
+            .await
+            .ok()?
+            .lines()
+            .rev()
+            .filter_map(|line| line.split_once('\t'))
+            .filter_map(|(_, entry)| serde_json::from_str::<IndexEntry>(entry).ok())
+            .find(|entry| entry.key == key)?
+            .integrity
+    }
+}
+
+/// Hashes everything read through it, to check it against an integrity string once read.
+pub struct IntegrityReader<R> {
+    inner: R,
+    algorithm: &'static str,
+    hasher: Box<dyn DynDigest + Send>,
+    expected: Option<Vec<u8>>,
+}
+
+impl<R: Read> IntegrityReader<R> {
+    /// Check `inner` against the strongest sha2 hash of `integrity`. Integrity strings without
+    /// one, like a lone `sha1-`, can't be checked, so the content is hashed with sha512 instead.
+    pub fn new(inner: R, integrity: Option<&str>) -> Self {
+        let (algorithm, expected) = match integrity.and_then(strongest_hash) {
+            Some((algorithm, digest)) => (algorithm, Some(digest)),
+            None => ("sha512", None),
+        };
+
+        Self {
+            inner,
+            algorithm,
+            hasher: hasher(algorithm),
+            expected,
+        }
+    }
+
+    /// The integrity of everything read, or `None` if it doesn't match the expected one.
+    pub fn verify(self) -> Option<String> {
+        let digest = self.hasher.finalize();
+        if self.expected.is_some_and(|expected| *expected != *digest) {
+            return None;
+        }
+
+        Some(format!("{}-{}", self.algorithm, STANDARD.encode(digest)))
+    }
+}
+
+impl<R: Read> Read for IntegrityReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+
+        Ok(read)
+    }
+}
+
+/// The strongest sha2 hash of a subresource integrity string, decoded.
+fn strongest_hash(integrity: &str) -> Option<(&'static str, Vec<u8>)> {
+    ["sha512", "sha384", "sha256"]
+        .into_iter()
+        .find_map(|algorithm| {
+            let digest = integrity.split_whitespace().find_map(|hash| {
+                hash.strip_prefix(algorithm)?
+                    .strip_prefix('-')?
+                    .split('?')
+                    .next()
+            })?;
+
+            Some((algorithm, STANDARD.decode(digest).ok()?))
+        })
+}
+
+fn hasher(algorithm: &str) -> Box<dyn DynDigest + Send> {
+    match algorithm {
+        "sha512" => Box::new(Sha512::default()),
+        "sha384" => Box::new(Sha384::default()),
+        _ => Box::new(Sha256::default()),
+    }
+}
+
+/// The index bucket holding the entries of `key`, `index-v5/ab/cd/ef…`.
+fn bucket_path(dir: &Path, key: &str) -> PathBuf {
+    let bucket = to_hex(&Sha256::digest(key.as_bytes()));
+    dir.join("index-v5")
+        .join(&bucket[..2])
+        .join(&bucket[2..4])
+        .join(&bucket[4..])
+}
+
+/// Where content with the `algorithm` hash `digest` is kept, `content-v2/sha512/ab/cd/ef…`.
+fn content_path(dir: &Path, algorithm: &str, digest: &[u8]) -> PathBuf {
+    let hex = to_hex(digest);
+    dir.join("content-v2")
+        .join(algorithm)
+        .join(&hex[..2])
+        .join(&hex[2..4])
+        .join(&hex[4..])
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URL: &str = "https://registry.npmjs.org/a/-/a-1.0.0.tgz";
+
+    /// Add `content` to a `_cacache` at `dir` like npm does, returning its integrity.
+    fn add_to_cache(dir: &Path, content: &[u8]) -> String {
+        let digest = Sha512::digest(content);
+        let integrity = format!("sha512-{}", STANDARD.encode(digest));
+
+        let hex = to_hex(&digest);
+        let content_path = dir
+            .join("content-v2/sha512")
+            .join(&hex[..2])
+            .join(&hex[2..4]);
+        std::fs::create_dir_all(&content_path).unwrap();
+        std::fs::write(content_path.join(&hex[4..]), content).unwrap();
+
+        let key = format!("{REQUEST_CACHE_KEY_PREFIX}{URL}");
+        let bucket = to_hex(&Sha256::digest(key.as_bytes()));
+        let bucket_path = dir.join("index-v5").join(&bucket[..2]).join(&bucket[2..4]);
+        std::fs::create_dir_all(&bucket_path).unwrap();
+        let entry = serde_json::json!({ "key": key, "integrity": integrity, "time": 0 });
+        std::fs::write(bucket_path.join(&bucket[4..]), format!("\nhash\t{}", entry)).unwrap();
+
+        integrity
+    }
+
+    /// Read a cached tarball through its integrity check, like installs do.
+    async fn read_tarball(cache: &Cacache, integrity: Option<&str>, url: &str) -> Option<Vec<u8>> {
+        let (path, integrity) = cache.find_tarball(integrity, url).await?;
+        let mut reader = IntegrityReader::new(std::fs::File::open(path).unwrap(), Some(&integrity));
+        let mut content = vec![];
+        reader.read_to_end(&mut content).unwrap();
+
+        reader.verify().map(|_| content)
+    }
+
+    #[tokio::test]
+    async fn reads_tarballs_by_integrity_or_url() {
+        let dir = tempfile::tempdir().unwrap();
+        let integrity = add_to_cache(dir.path(), b"tarball");
+        let cache = Cacache {
+            dir: Some(dir.path().to_path_buf()),
+        };
+
+        assert_eq!(
+            read_tarball(&cache, Some(&integrity), URL).await.as_deref(),
+            Some(&b"tarball"[..])
+        );
+        assert_eq!(
+            read_tarball(&cache, None, URL).await.as_deref(),
+            Some(&b"tarball"[..])
+        );
+        assert_eq!(
+            read_tarball(&cache, None, "https://registry.npmjs.org/b/-/b-1.0.0.tgz").await,
+            None
+        );
+        assert_eq!(
+            read_tarball(&Cacache::default(), Some(&integrity), URL).await,
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn ignores_corrupt_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let integrity = add_to_cache(dir.path(), b"tarball");
+        let hex = to_hex(&strongest_hash(&integrity).unwrap().1);
+        std::fs::write(
+            dir.path()
+                .join("content-v2/sha512")
+                .join(&hex[..2])
+                .join(&hex[2..4])
+                .join(&hex[4..]),
+            b"corrupt",
+        )
+        .unwrap();
+
+        let cache = Cacache {
+            dir: Some(dir.path().to_path_buf()),
+        };
+        assert_eq!(read_tarball(&cache, Some(&integrity), URL).await, None);
+    }
+
+    #[test]
+    fn computes_integrity_while_reading() {
+        let integrity = format!("sha512-{}", STANDARD.encode(Sha512::digest(b"tarball")));
+        let read = |integrity: Option<&str>| {
+            let mut reader = IntegrityReader::new(&b"tarball"[..], integrity);
+            io::copy(&mut reader, &mut io::sink()).unwrap();
+            reader.verify()
+        };
+
+        assert_eq!(read(Some(&integrity)), Some(integrity.clone()));
+        // Content without a sha2 integrity is hashed with sha512.
+        assert_eq!(read(None), Some(integrity.clone()));
+        assert_eq!(read(Some("sha1-AAAA")), Some(integrity));
+        assert_eq!(read(Some("sha256-AAAA")), None);
+    }
+
+    #[tokio::test]
+    async fn writes_tarballs_and_index_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cacache {
+            dir: Some(dir.path().to_path_buf()),
+        };
+        let integrity = format!("sha512-{}", STANDARD.encode(Sha512::digest(b"tarball")));
+        let tarball = dir.path().join("a-1.0.0.tgz.partial");
+        std::fs::write(&tarball, b"tarball").unwrap();
+
+        cache
+            .write_tarball(&tarball, &integrity, URL)
+            .await
+            .unwrap();
+        cache
+            .write_tarball(&tarball, &integrity, URL)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            read_tarball(&cache, Some(&integrity), URL).await.as_deref(),
+            Some(&b"tarball"[..])
+        );
+        // Packages with only a sha1 integrity are found by their URL.
+        for integrity in [None, Some("sha1-AAAA")] {
+            assert_eq!(
+                read_tarball(&cache, integrity, URL).await.as_deref(),
+                Some(&b"tarball"[..])
+            );
+        }
+        let key = format!("{REQUEST_CACHE_KEY_PREFIX}{URL}");
+        let index = std::fs::read_to_string(bucket_path(dir.path(), &key)).unwrap();
+        assert_eq!(index.lines().filter(|line| !line.is_empty()).count(), 1);
+        let hex = to_hex(&Sha512::digest(b"tarball"));
+        assert!(dir
+            .path()
+            .join(format!(
+                "content-v2/sha512/{}/{}/{}",
+                &hex[..2],
+                &hex[2..4],
+                &hex[4..]
+            ))
+            .exists());
+    }
+
+    #[test]
+    fn picks_strongest_hash() {
+        let (algorithm, _) = strongest_hash("sha1-AAAA sha256-AAAA sha512-AAAA?foo").unwrap();
+        assert_eq!(algorithm, "sha512");
+        assert_eq!(strongest_hash("sha1-AAAA"), None);
+    }
+}
@@ -0,0 +1,201 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde_json::Value;
+use thiserror::Error;
+use tokio::task;
+
+use crate::{
+    npm::VersionRangeSpecifier,
+    package_manifest::{get_workspace_manifest_file, ManifestError},
+};
+
+const CATALOG_PROTOCOL: &str = "catalog:";
+const DEFAULT_CATALOG: &str = "default";
+
+/// The manifest fields whose `catalog:` specs are replaced when packing.
+const DEPENDENCY_FIELDS: [&str; 4] = [
+    "dependencies",
+    "devDependencies",
+    "optionalDependencies",
+    "peerDependencies",
+];
+
+#[derive(Error, Debug, PartialEq)]
+pub enum CatalogError {
+    #[error("Catalog {0} is not defined")]
+    CatalogNotFound(String),
+    #[error("Catalog {0} has no entry for {1}")]
+    EntryNotFound(String, String),
+}
+
+type Catalog = BTreeMap<String, VersionRangeSpecifier>;
+
+/// Version ranges shared by the members of a workspace,
+/// referenced with `catalog:` for the default catalog or `catalog:<name>` for a named one.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Catalogs {
+    catalogs: BTreeMap<String, Catalog>,
+}
+
+impl Catalogs {
+    /// Read `catalog` and `catalogs` from the manifest,
+    /// either at the top level or under `workspaces`.
+    pub fn from_manifest(manifest: &Value) -> anyhow::Result<Self> {
+        let mut catalogs = BTreeMap::new();
+
+        for source in [Some(manifest), manifest.get("workspaces")]
+            .into_iter()
+            .flatten()
+        {
+            if let Some(catalog) = source.get("catalog") {
+                catalogs.insert(
+                    DEFAULT_CATALOG.to_string(),
+                    serde_json::from_value(catalog.to_owned())?,
+                );
+            }
+
+            if let Some(named) = source.get("catalogs") {
+                let named: BTreeMap<String, Catalog> = serde_json::from_value(named.to_owned())?;
+                catalogs.extend(named);
+            }
+        }
+
+        Ok(Self { catalogs })
+    }
+
+    /// Replace a `catalog:` spec of `package_name` with the range from the catalog.
+    /// Any other spec is returned as is.
+    pub fn resolve(
+        &self,
+        package_name: &str,
+        version_range: &VersionRangeSpecifier,
+    ) -> Result<VersionRangeSpecifier, CatalogError> {
+        let catalog_name = match version_range.strip_prefix(CATALOG_PROTOCOL) {
+            Some("") => DEFAULT_CATALOG,
+            Some(catalog_name) => catalog_name,
+            None => return Ok(version_range.to_owned()),
+        };
+
+        self.catalogs
+            .get(catalog_name)
+            .ok_or_else(|| CatalogError::CatalogNotFound(catalog_name.to_string()))?
+            .get(package_name)
+            .cloned()
+            .ok_or_else(|| {
+                CatalogError::EntryNotFound(catalog_name.to_string(), package_name.to_string())
+            })
+    }
+
+    pub fn resolve_dependencies(
+        &self,
+        deps: HashMap<String, VersionRangeSpecifier>,
+    ) -> Result<HashMap<String, VersionRangeSpecifier>, CatalogError> {
+        deps.into_iter()
+            .map(|(name, range)| {
+                let range = self.resolve(&name, &range)?;
+                Ok((name, range))
+            })
+            .collect()
+    }
+
+    /// Replace the `catalog:` specs of every dependency field of a manifest being packed,
+    /// since consumers of the published package can't resolve them.
+    pub fn replace_manifest_specs(&self, manifest: &mut Value) -> Result<(), CatalogError> {
+        for field in DEPENDENCY_FIELDS {
+            let Some(Value::Object(deps)) = manifest.get_mut(field) else {
+                continue;
+            };
+
+            for (name, spec) in deps.iter_mut() {
+                if let Value::String(range) = spec {
+                    let resolved =
+                        self.resolve(name, &VersionRangeSpecifier::new(range.clone()))?;
+                    *spec = Value::String(resolved.into());
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Read the catalogs of the workspace the current project belongs to.
+pub async fn read_catalogs() -> anyhow::Result<Catalogs> {
+    match task::spawn_blocking(get_workspace_manifest_file).await? {
+        Ok(manifest) => Catalogs::from_manifest(&manifest),
+        Err(error) if error.downcast_ref::<ManifestError>().is_some() => Ok(Catalogs::default()),
+        Err(error) => Err(error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn catalogs() -> Catalogs {
+        Catalogs::from_manifest(&json!({
+            "name": "monorepo",
+            "workspaces": {
+                "packages": ["packages/*"],
+                "catalog": { "react": "^18.2.0" },
+            },
+            "catalogs": {
+                "legacy": { "react": "^16.14.0" }
+            }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn resolves_catalog_specs() {
+        let catalogs = catalogs();
+        let range = |range: &str| VersionRangeSpecifier::new(range.to_string());
+
+        assert_eq!(
+            catalogs.resolve("react", &range("catalog:")),
+            Ok(range("^18.2.0"))
+        );
+        assert_eq!(
+            catalogs.resolve("react", &range("catalog:default")),
+            Ok(range("^18.2.0"))
+        );
+        assert_eq!(
+            catalogs.resolve("react", &range("catalog:legacy")),
+            Ok(range("^16.14.0"))
+        );
+        assert_eq!(catalogs.resolve("react", &range("^17")), Ok(range("^17")));
+        assert_eq!(
+            catalogs.resolve("vue", &range("catalog:")),
+            Err(CatalogError::EntryNotFound(
+                String::from("default"),
+                String::from("vue")
+            ))
+        );
+        assert_eq!(
+            catalogs.resolve("react", &range("catalog:next")),
+            Err(CatalogError::CatalogNotFound(String::from("next")))
+        );
+    }
+
+    #[test]
+    fn replaces_manifest_specs() {
+        let mut manifest = json!({
+            "name": "member",
+            "dependencies": { "react": "catalog:", "uuid": "^9.0.0" },
+            "peerDependencies": { "react": "catalog:legacy" }
+        });
+
+        catalogs().replace_manifest_specs(&mut manifest).unwrap();
+
+        assert_eq!(
+            manifest,
+            json!({
+                "name": "member",
+                "dependencies": { "react": "^18.2.0", "uuid": "^9.0.0" },
+                "peerDependencies": { "react": "^16.14.0" }
+            })
+        );
+    }
+}
@@ -0,0 +1,320 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::npm::{NpmPackageVersion, Version};
+
+/// A stable identifier of a package instance in a [`DependencyGraph`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(usize);
+
+/// A single resolved package version and the package instances it depends on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DependencyNode {
+    pub version: NpmPackageVersion,
+    /// Whether the package is a direct dependency of the project.
+    pub is_root: bool,
+    pub dependencies: Vec<NodeId>,
+    /// The packages resolved within the shrinkwrap of this package, if it publishes one.
+    pub shrinkwrapped: Vec<NodeId>,
+}
+
+/// The resolved dependency graph, with one node per `name@version`.
+/// The graph may contain cycles, which all traversals handle.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct DependencyGraph {
+    nodes: Vec<DependencyNode>,
+    dependents: Vec<Vec<NodeId>>,
+    index: HashMap<(String, Version), NodeId>,
+}
+
+impl DependencyGraph {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a package instance, or get the existing node of the same `name@version`.
+    pub fn add_node(&mut self, version: NpmPackageVersion, is_root: bool) -> NodeId {
+        let key = (version.name.clone(), version.version.clone());
+
+        if let Some(id) = self.index.get(&key) {
+            self.nodes[id.0].is_root |= is_root;
+            return *id;
+        }
+
+        let id = NodeId(self.nodes.len());
+        self.nodes.push(DependencyNode {
+            version,
+            is_root,
+            dependencies: vec![],
+            shrinkwrapped: vec![],
+        });
+        self.dependents.push(vec![]);
+        self.index.insert(key, id);
+
+        id
+    }
+
+    /// Add an edge from a package to one of its dependencies.
+    pub fn add_edge(&mut self, from: NodeId, to: NodeId) {
+        if self.nodes[from.0].dependencies.contains(&to) {
+            return;
+        }
+
+        self.nodes[from.0].dependencies.push(to);
+        self.dependents[to.0].push(from);
+    }
+
+    /// Record that `to` was resolved within the shrinkwrap of `from`.
+    pub fn add_shrinkwrapped(&mut self, from: NodeId, to: NodeId) {
+        if !self.nodes[from.0].shrinkwrapped.contains(&to) {
+            self.nodes[from.0].shrinkwrapped.push(to);
+        }
+    }
+
+    pub fn node(&self, id: NodeId) -> &DependencyNode {
+        &self.nodes[id.0]
+    }
+
+    pub fn find(&self, name: &str, version: &Version) -> Option<NodeId> {
+        self.index
+            .get(&(name.to_string(), version.to_owned()))
+            .copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// All nodes, in insertion order.
+    pub fn nodes(&self) -> impl Iterator<Item = (NodeId, &DependencyNode)> {
+        self.nodes
+            .iter()
+            .enumerate()
+            .map(|(index, node)| (NodeId(index), node))
+    }
+
+    /// The direct dependencies of the project.
+    pub fn roots(&self) -> impl Iterator<Item = NodeId> + '_ {
+        self.nodes()
+            .filter(|(_, node)| node.is_root)
+            .map(|(id, _)| id)
+    }
+
+    /// The packages that depend on `id`.
+    pub fn dependents(&self, id: NodeId) -> &[NodeId] {
+        &self.dependents[id.0]
+    }
+
+    /// All nodes ordered so that dependencies come before their dependents.
+    /// Edges that close a cycle are ignored.
+    pub fn topological_order(&self) -> Vec<NodeId> {
+        let mut order = Vec::with_capacity(self.nodes.len());
+        self.depth_first(|event| {
+            if let Visit::Exit(id) = event {
+                order.push(id);
+            }
+        });
+
+        order
+    }
+
+    /// Every cycle in the graph, each starting and ending with the same node.
+    pub fn cycles(&self) -> Vec<Vec<NodeId>> {
+        let mut cycles = vec![];
+        self.depth_first(|event| {
+            if let Visit::BackEdge(path, to) = event {
+                let start = path
+                    .iter()
+                    .position(|id| *id == to)
+                    .expect("back edge target must be on the path");
+
+                let mut cycle = path[start..].to_vec();
+                cycle.push(to);
+                cycles.push(cycle);
+            }
+        });
+
+        cycles
+    }
+
+    /// The shortest path from `id` to a direct dependency of the project, starting with `id`.
+    /// Found breadth first, so it's linear in the size of the graph.
+    pub fn shortest_path_to_root(&self, id: NodeId) -> Option<Vec<NodeId>> {
+        // The node each visited node was reached from, on the way back to `id`.
+        let mut reached_from = vec![None; self.nodes.len()];
+        let mut visited = vec![false; self.nodes.len()];
+        let mut queue = VecDeque::from([id]);
+        visited[id.0] = true;
+
+        while let Some(current) = queue.pop_front() {
+            if self.node(current).is_root {
+                let mut path = vec![current];
+                while let Some(previous) = reached_from[path[path.len() - 1].0] {
+                    path.push(previous);
+                }
+                path.reverse();
+
+                return Some(path);
+            }
+
+            for dependent in self.dependents(current) {
+                if !visited[dependent.0] {
+                    visited[dependent.0] = true;
+                    reached_from[dependent.0] = Some(current);
+                    queue.push_back(*dependent);
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Iterative depth first traversal over the whole graph, starting from the roots.
+    fn depth_first(&self, mut visit: impl FnMut(Visit)) {
+        #[derive(Clone, Copy, PartialEq)]
+        enum State {
+            New,
+            OnPath,
+            Done,
+        }
+
+        let mut states = vec![State::New; self.nodes.len()];
+        let starts = self.roots().chain(self.nodes().map(|(id, _)| id));
+
+        for start in starts {
+            if states[start.0] != State::New {
+                continue;
+            }
+
+            states[start.0] = State::OnPath;
+            let mut path = vec![start];
+            let mut next_edge = vec![0];
+
+            while let Some(&id) = path.last() {
+                let edge = next_edge
+                    .last_mut()
+                    .expect("path and edges have equal length");
+
+                match self.nodes[id.0].dependencies.get(*edge) {
+                    Some(&dep) => {
+                        *edge += 1;
+                        match states[dep.0] {
+                            State::New => {
+                                states[dep.0] = State::OnPath;
+                                path.push(dep);
+                                next_edge.push(0);
+                            }
+                            State::OnPath => visit(Visit::BackEdge(&path, dep)),
+                            State::Done => {}
+                        }
+                    }
+                    None => {
+                        states[id.0] = State::Done;
+                        path.pop();
+                        next_edge.pop();
+                        visit(Visit::Exit(id));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Format a list of nodes as `a@1.0.0 -> b@2.0.0`.
+    pub fn format_path(&self, path: &[NodeId]) -> String {
+        path.iter()
+            .map(|id| {
+                let version = &self.node(*id).version;
+                format!("{}@{}", version.name, version.version)
+            })
+            .collect::<Vec<_>>()
+            .join(" -> ")
+    }
+}
+
+enum Visit<'a> {
+    /// All dependencies of the node were visited.
+    Exit(NodeId),
+    /// An edge from the end of the current path to a node already on it.
+    BackEdge(&'a [NodeId], NodeId),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn package_version(name: &str) -> NpmPackageVersion {
+        NpmPackageVersion::for_test(name, "1.0.0")
+    }
+
+    /// root -> a -> b -> c -> a, root -> c
+    fn cyclic_graph() -> (DependencyGraph, [NodeId; 4]) {
+        let mut graph = DependencyGraph::new();
+        let root = graph.add_node(package_version("root"), true);
+        let a = graph.add_node(package_version("a"), false);
+        let b = graph.add_node(package_version("b"), false);
+        let c = graph.add_node(package_version("c"), false);
+
+        graph.add_edge(root, a);
+        graph.add_edge(a, b);
+        graph.add_edge(b, c);
+        graph.add_edge(c, a);
+        graph.add_edge(root, c);
+
+        (graph, [root, a, b, c])
+    }
+
+    #[test]
+    fn add_node_dedupes_versions() {
+        let mut graph = DependencyGraph::new();
+        let a = graph.add_node(package_version("a"), false);
+        let a_again = graph.add_node(package_version("a"), true);
+
+        assert_eq!(a, a_again);
+        assert_eq!(graph.len(), 1);
+        assert!(graph.node(a).is_root);
+    }
+
+    #[test]
+    fn detects_cycles() {
+        let (graph, [_, a, b, c]) = cyclic_graph();
+
+        assert_eq!(graph.cycles(), vec![vec![a, b, c, a]]);
+        assert_eq!(graph.format_path(&[a, b]), "a@1.0.0 -> b@1.0.0");
+    }
+
+    #[test]
+    fn topological_order_ignores_back_edges() {
+        let (graph, [root, a, b, c]) = cyclic_graph();
+
+        assert_eq!(graph.topological_order(), vec![c, b, a, root]);
+    }
+
+    #[test]
+    fn shortest_path_to_root_is_breadth_first() {
+        let (graph, [root, a, b, c]) = cyclic_graph();
+
+        assert_eq!(graph.shortest_path_to_root(b), Some(vec![b, a, root]));
+        assert_eq!(graph.shortest_path_to_root(c), Some(vec![c, root]));
+        assert_eq!(graph.shortest_path_to_root(root), Some(vec![root]));
+    }
+
+    #[test]
+    fn deep_graph_does_not_overflow() {
+        let mut graph = DependencyGraph::new();
+        let mut previous = graph.add_node(package_version("0"), true);
+        for index in 1..100_000 {
+            let next = graph.add_node(package_version(&index.to_string()), false);
+            graph.add_edge(previous, next);
+            previous = next;
+        }
+
+        assert_eq!(graph.topological_order().len(), 100_000);
+        assert_eq!(
+            graph.shortest_path_to_root(previous).map(|path| path.len()),
+            Some(100_000)
+        );
+    }
+}
@@ -1,258 +1,1135 @@
-use std::collections::{HashMap, HashSet};
+use std::{
+    collections::{hash_map::Entry, BTreeMap, BTreeSet, HashMap},
+    future::Future,
+    sync::Arc,
+};
 
+use anyhow::Context;
 use futures::{stream::FuturesUnordered, StreamExt};
 
 use crate::{
     config::Config,
-    http::get_npm_package,
-    npm::{NpmPackageVersion, ResolvedDependencies, ResolvedDependencyTree, VersionRangeSpecifier},
-    resolve_version_range::resolve_version_from_version_range,
+    dependency_graph::DependencyGraph,
+    jsr::parse_jsr_spec,
+    lockfile::get_package_key,
+    npm::{NpmPackageVersion, NpmResolvedPackage, Version, VersionRangeSpecifier},
+    package_extensions::apply_package_extensions,
+    packument_cache::PackumentCache,
+    resolve_version_range::{resolve_version_from_version_range, ResolveOptions},
+    shrinkwrap::{fetch_shrinkwrap, Shrinkwrap},
 };
 
-#[derive(Debug, derive_more::Display, derive_more::Error)]
-pub enum Error {
-    DependencyResolveError,
-    VersionDoesNotExist,
+/// Versions from a previous resolution, reused when they satisfy a requested range
+/// so that unrelated packages stay pinned.
+#[derive(Debug, Default, Clone)]
+pub struct LockedVersions {
+    versions: HashMap<String, Vec<NpmPackageVersion>>,
+    /// The versions pinned by the shrinkwrap of a locked package, keyed by `name@version`.
+    shrinkwraps: HashMap<String, Vec<NpmPackageVersion>>,
+    /// The registry that served each package, when it isn't the first registry of the package.
+    registries: HashMap<String, String>,
+}
+
+impl LockedVersions {
+    pub fn new(versions: impl IntoIterator<Item = NpmPackageVersion>) -> Self {
+        let mut locked = Self::default();
+        locked.extend(versions);
+
+        locked
+    }
+
+    /// Add more versions, such as the ones pinned by a shrinkwrap.
+    pub fn extend(&mut self, versions: impl IntoIterator<Item = NpmPackageVersion>) {
+        for version in versions {
+            self.versions
+                .entry(version.name.clone())
+                .or_default()
+                .push(version);
+        }
+    }
+
+    /// Record the versions pinned by the shrinkwrap of `version`,
+    /// which are reused along with it.
+    pub fn add_shrinkwrap(&mut self, version: &NpmPackageVersion, pinned: Vec<NpmPackageVersion>) {
+        self.shrinkwraps
+            .insert(get_package_key(&version.name, &version.version), pinned);
+    }
+
+    /// Record the registry that served `package_name`, which its packument is fetched from
+    /// when it has to be resolved again.
+    pub fn set_registry(&mut self, package_name: &str, registry: String) {
+        self.registries.insert(package_name.to_string(), registry);
+    }
+
+    /// The registry that served `package_name`, if it was recorded.
+    pub fn registry(&self, package_name: &str) -> Option<&str> {
+        self.registries.get(package_name).map(String::as_str)
+    }
+
+    /// Resolve `package_name` from scratch instead of reusing its locked versions.
+    pub fn unlock(&mut self, package_name: &str) {
+        self.versions.remove(package_name);
+    }
+
+    /// Get the highest locked version of `package_name` that satisfies `version_range`,
+    /// or the lowest one if `lowest` is set.
+    /// Tags such as `latest` never match, as they may have moved since.
+    fn find(
+        &self,
+        package_name: &str,
+        version_range: &VersionRangeSpecifier,
+        lowest: bool,
+    ) -> Option<&NpmPackageVersion> {
+        let version_range = match parse_jsr_spec(package_name, version_range) {
+            Ok(Some((_, range))) => range,
+            _ => version_range.to_owned(),
+        };
+        let version_req: node_semver::Range = version_range.parse().ok()?;
+
+        let matching = self
+            .versions
+            .get(package_name)?
+            .iter()
+            .filter_map(|version| {
+                let parsed: node_semver::Version = version.version.parse().ok()?;
+                version_req.satisfies(&parsed).then_some((parsed, version))
+            });
+
+        let found = if lowest {
+            matching.min_by(|(a, _), (b, _)| a.cmp(b))
+        } else {
+            matching.max_by(|(a, _), (b, _)| a.cmp(b))
+        };
+        found.map(|(_, version)| version)
+    }
+
+    /// The locked version `find` picks, with the versions its shrinkwrap pins.
+    fn find_with_shrinkwrap(
+        &self,
+        package_name: &str,
+        version_range: &VersionRangeSpecifier,
+        lowest: bool,
+    ) -> Option<LockedVersion> {
+        let version = self.find(package_name, version_range, lowest)?;
+        let shrinkwrapped = self
+            .shrinkwraps
+            .get(&get_package_key(&version.name, &version.version))
+            .cloned()
+            .unwrap_or_default();
+
+        Some((version.to_owned(), shrinkwrapped))
+    }
 }
 
+/// A version reused from a previous resolution, and the versions pinned by its shrinkwrap.
+type LockedVersion = (NpmPackageVersion, Vec<NpmPackageVersion>);
+
+/// The shrinkwrapped package, as `name@version`, whose shrinkwrap pins the ranges requested
+/// within its dependency tree. Ranges requested outside of any shrinkwrap have no scope.
+type Scope = Option<String>;
+
+/// A range requested of a package within a scope, which is resolved once.
+type RequestKey = (String, VersionRangeSpecifier, Scope);
+
+/// The resolved version of every range requested within a scope, whether it was requested
+/// by the root, and the scope its own dependencies are requested in.
+type ResolvedVersions =
+    BTreeMap<String, BTreeMap<(VersionRangeSpecifier, Scope), (NpmPackageVersion, bool, Scope)>>;
+
 pub async fn resolve_deps(
     deps: HashMap<String, VersionRangeSpecifier>,
+    locked: &LockedVersions,
     client: &Config,
-) -> anyhow::Result<Vec<ResolvedDependencies>> {
-    // let mut package_to_get_from_npm = HashSet::new();
-    let mut futures = FuturesUnordered::new();
-    let mut fetched_packages = HashSet::new();
+) -> anyhow::Result<DependencyGraph> {
+    resolve_deps_with(
+        deps,
+        locked,
+        client,
+        |package_name| async move {
+            let preferred_registry = locked.registry(&package_name);
+            client
+                .registry
+                .get_package(&package_name, preferred_registry, client)
+                .await
+        },
+        |version| async move { fetch_shrinkwrap(&version, client).await },
+    )
+    .await
+}
 
-    for (dep_name, dep_version_range) in deps {
-        // package_to_get_from_npm.insert((dep_name, dep_version_range, true));
-        if fetched_packages.contains(&dep_name) {
-            continue;
+/// Resolve `deps` using `fetch_package` to get packuments, which is called once per package,
+/// and `fetch_shrinkwrap` to get the shrinkwrap of packages that publish one.
+/// Packages are only fetched if none of the `locked` versions satisfy the requested range.
+/// Optional dependencies, and everything only they depend on, are left out when they can't
+/// be resolved. The result does not depend on the order in which fetches complete.
+pub async fn resolve_deps_with<F, Fut, S, SFut>(
+    deps: HashMap<String, VersionRangeSpecifier>,
+    locked: &LockedVersions,
+    client: &Config,
+    fetch_package: F,
+    fetch_shrinkwrap: S,
+) -> anyhow::Result<DependencyGraph>
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = anyhow::Result<NpmResolvedPackage>>,
+    S: Fn(NpmPackageVersion) -> SFut,
+    SFut: Future<Output = anyhow::Result<Option<Shrinkwrap>>>,
+{
+    // Every range requested of a package shares a single fetch of its packument.
+    let packuments = PackumentCache::default();
+    let fetch_cached = |package_name: String| {
+        let packuments = &packuments;
+        let fetch_package = &fetch_package;
+        async move {
+            packuments
+                .get_or_fetch(&package_name, || fetch_package(package_name.clone()))
+                .await
         }
-        let future = get_npm_package_version(dep_name.clone(), dep_version_range, true, client);
-        fetched_packages.insert(dep_name);
+    };
+
+    let mut futures = FuturesUnordered::new();
+    // Whether each requested range is only requested optionally.
+    let mut requested_ranges = HashMap::<RequestKey, bool>::new();
+    // Why the optional ranges that were left out failed to resolve.
+    let mut skipped = HashMap::<RequestKey, anyhow::Error>::new();
+    // Versions pinned by the shrinkwraps of resolved packages, keyed by their scope.
+    let mut shrinkwraps = HashMap::<String, LockedVersions>::new();
+
+    for (dep_name, dep_version_range) in client.catalogs.resolve_dependencies(deps)? {
+        requested_ranges.insert((dep_name.clone(), dep_version_range.clone(), None), false);
+        let future = get_npm_package_version(
+            &fetch_cached,
+            &fetch_shrinkwrap,
+            locked.find_with_shrinkwrap(
+                &dep_name,
+                &dep_version_range,
+                client.resolve_options(&dep_name, true).lowest,
+            ),
+            RequestedRange {
+                package_name: dep_name,
+                range: dep_version_range,
+                is_root: true,
+                scope: None,
+            },
+            client,
+        );
         futures.push(future);
     }
 
-    let mut resolved_versions: HashMap<
-        String,
-        HashMap<VersionRangeSpecifier, (NpmPackageVersion, bool)>,
-    > = HashMap::new();
-
-    // while !package_to_get_from_npm.is_empty() {
-    // let mut futures = FuturesUnordered::new();
-    // for package in package_to_get_from_npm.iter() {
-    //     if fetched_packages.contains(&package.0) {
-    //         continue;
-    //     }
-    //     let future =
-    //         get_npm_package_version(package.0.clone(), package.1.clone(), package.2, client);
-    //     fetched_packages.insert(package.0.clone());
-    //     futures.push(future);
-    // }
-
-    // let mut versions: Vec<(
-    //     VersionRangeSpecifier,
-    //     Result<NpmPackageVersion, anyhow::Error>,
-    //     bool,
-    // )> = vec![];
-
-    // for fut in futures
-    //     .take(100)
-    //     .collect::<Vec<(
-    //         VersionRangeSpecifier,
-    //         Result<NpmPackageVersion, anyhow::Error>,
-    //         bool,
-    //     )>>()
-    //     .await
-    // {
-    //     versions.push(fut);
-    // }
-    // versions.push(join_all(futures).await.iter().tak)
-    //  = join_all(futures).await;
-
-    // package_to_get_from_npm.clear();
+    let mut resolved_versions = ResolvedVersions::new();
+    let mut held_back = BTreeSet::new();
 
     loop {
         match futures.next().await {
-            Some(Ok((range, version, is_root))) => {
-                let version_clone = version.clone();
-                for (dep_name, dep_version_range) in version.dependencies {
-                    match resolved_versions.get(&dep_name) {
-                        Some(ranges) if !ranges.contains_key(&dep_version_range) => {
-                            let future =
-                                get_npm_package_version(dep_name, dep_version_range, false, client);
-                            // .then(|version| {
-                            // return (
-                            //     String::from(""),
-                            //     VersionRangeSpecifier::new(String::from("")),
-                            //     false,
-                            // );
-                            // });
-                            futures.push(future);
-                            // package_to_get_from_npm.insert((
-                            //     dep.0.to_owned(),
-                            //     dep.1.to_owned(),
-                            //     false,
-                            // ));
+            Some((
+                _,
+                Ok(ResolvedRange {
+                    range,
+                    version,
+                    is_root,
+                    scope,
+                    newest_version,
+                    shrinkwrapped,
+                }),
+            )) => {
+                if let Some(newest_version) = newest_version {
+                    held_back.insert(format!(
+                        "{}@{newest_version} (using {})",
+                        version.name, version.version
+                    ));
+                }
+
+                // A shrinkwrap only pins the dependency tree of the package that published it.
+                let dependency_scope = if shrinkwrapped.is_empty() {
+                    scope.clone()
+                } else {
+                    let key = get_package_key(&version.name, &version.version);
+                    shrinkwraps.insert(key.clone(), LockedVersions::new(shrinkwrapped));
+                    Some(key)
+                };
+                let pinned = dependency_scope
+                    .as_ref()
+                    .and_then(|scope| shrinkwraps.get(scope));
+
+                let key = (version.name.clone(), range.clone(), scope.clone());
+                let is_optional = requested_ranges.get(&key) == Some(&true);
+                let dependencies = version
+                    .dependencies
+                    .iter()
+                    .map(|dependency| (dependency, is_optional))
+                    .chain(
+                        version
+                            .optional_dependencies
+                            .iter()
+                            .map(|dependency| (dependency, true)),
+                    );
+
+                for ((dep_name, dep_version_range), optional) in dependencies {
+                    let request = (
+                        dep_name.clone(),
+                        dep_version_range.clone(),
+                        dependency_scope.clone(),
+                    );
+                    match requested_ranges.entry(request) {
+                        Entry::Vacant(entry) => {
+                            entry.insert(optional);
                         }
-                        Some(_) => {}
-                        None => {
-                            // package_to_get_from_npm.insert((
-                            //     dep.0.to_owned(),
-                            //     dep.1.to_owned(),
-                            //     false,
-                            // ));
+                        Entry::Occupied(mut entry) => {
+                            if !optional {
+                                entry.insert(false);
+                                if let Some(error) = skipped.remove(entry.key()) {
+                                    return Err(error);
+                                }
+                            }
+                            continue;
                         }
                     }
-                }
 
-                match resolved_versions.get_mut(&version.name) {
-                    Some(range_to_versions) => {
-                        range_to_versions.insert(range.clone(), (version_clone, is_root.clone()));
-                    }
-                    None => {
-                        let version_name = version.name.clone();
+                    let lowest = client.resolve_options(dep_name, false).lowest;
+                    // The shrinkwrap of the package takes precedence over the lockfile.
+                    let locked_version = pinned
+                        .and_then(|pinned| {
+                            pinned.find_with_shrinkwrap(dep_name, dep_version_range, lowest)
+                        })
+                        .or_else(|| {
+                            locked.find_with_shrinkwrap(dep_name, dep_version_range, lowest)
+                        });
+                    let future = get_npm_package_version(
+                        &fetch_cached,
+                        &fetch_shrinkwrap,
+                        locked_version,
+                        RequestedRange {
+                            package_name: dep_name.clone(),
+                            range: dep_version_range.clone(),
+                            is_root: false,
+                            scope: dependency_scope.clone(),
+                        },
+                        client,
+                    );
+                    futures.push(future);
+                }
 
-                        let mut range_to_version = HashMap::new();
-                        range_to_version.insert(range.clone(), (version_clone, is_root.clone()));
-                        resolved_versions.insert(version_name, range_to_version);
-                    }
+                resolved_versions
+                    .entry(version.name.clone())
+                    .or_default()
+                    .insert((range, scope), (version, is_root, dependency_scope));
+            }
+            Some((key, Err(error))) => {
+                if requested_ranges.get(&key) != Some(&true) {
+                    return Err(error);
                 }
+
+                let (package_name, range, _) = &key;
+                eprintln!(
+                    "warning: skipping optional dependency {package_name}@{}: {error:#}",
+                    range.as_str()
+                );
+                skipped.insert(key, error);
             }
-            Some(Err(_)) => {}
             None => {
                 break;
             }
         }
     }
-    // while let Some(Ok((range, version, is_root))) = &futures.next().await {
-    //     // match version {
-    //     //     Ok(version) => {
-    //     for (dep_name, dep_version_range) in &version.dependencies {
-    //         match resolved_versions.get(dep_name) {
-    //             Some(ranges) if !ranges.contains_key(dep_version_range) => {
-    //                 let name = String::from("");
-    //                 let future =
-    //                     get_npm_package_version(dep_name, dep_version_range, false, client);
-    //                 // .then(|version| {
-    //                 // return (
-    //                 //     String::from(""),
-    //                 //     VersionRangeSpecifier::new(String::from("")),
-    //                 //     false,
-    //                 // );
-    //                 // });
-    //                 futures.push(future);
-    //                 // package_to_get_from_npm.insert((
-    //                 //     dep.0.to_owned(),
-    //                 //     dep.1.to_owned(),
-    //                 //     false,
-    //                 // ));
-    //             }
-    //             Some(_) => {}
-    //             None => {
-    //                 // package_to_get_from_npm.insert((
-    //                 //     dep.0.to_owned(),
-    //                 //     dep.1.to_owned(),
-    //                 //     false,
-    //                 // ));
-    //             }
-    //         }
-    //     }
-
-    //     match resolved_versions.get_mut(&version.name) {
-    //         Some(range_to_versions) => {
-    //             range_to_versions.insert(range.clone(), (version.clone(), is_root.clone()));
-    //         }
-    //         None => {
-    //             let version_name = version.name.clone();
-
-    //             let mut range_to_version = HashMap::new();
-    //             range_to_version.insert(range.clone(), (version.clone(), is_root.clone()));
-    //             resolved_versions.insert(version_name, range_to_version);
-    //         }
-    //     }
-    //     //     }
-    //     //     Err(error) => {
-    //     //         println!("{:?}", error);
-    //     //         return Err(Error::DependencyResolveError.into());
-    //     //     }
-    //     // }
-    // }
-    // }
-
-    construct_dependency_vec(resolved_versions)
+
+    if !held_back.is_empty() {
+        println!("held back versions newer than minimum-release-age:");
+        for package in held_back {
+            println!("  {package}");
+        }
+    }
+
+    Ok(construct_dependency_graph(resolved_versions))
 }
 
-pub fn construct_dependency_vec(
-    resolved: HashMap<String, HashMap<VersionRangeSpecifier, (NpmPackageVersion, bool)>>,
-) -> anyhow::Result<Vec<ResolvedDependencies>> {
-    let mut resolved_deps = vec![];
+pub fn construct_dependency_graph(resolved: ResolvedVersions) -> DependencyGraph {
+    let mut graph = DependencyGraph::new();
+    let mut node_ids = BTreeMap::new();
+    let mut package_ids = HashMap::new();
+
+    for (package, ranges) in &resolved {
+        for (request, (version, is_root, _)) in ranges {
+            let id = graph.add_node(version.to_owned(), *is_root);
+            node_ids.insert((package.to_owned(), request.to_owned()), id);
+            package_ids.insert(get_package_key(&version.name, &version.version), id);
+        }
+    }
+
+    // Every range requested within a shrinkwrap belongs to the package that published it.
+    for (package, ranges) in &resolved {
+        for request in ranges.keys() {
+            if let Some(shrinkwrapped_id) =
+                request.1.as_ref().and_then(|scope| package_ids.get(scope))
+            {
+                let id = node_ids[&(package.to_owned(), request.to_owned())];
+                graph.add_shrinkwrapped(*shrinkwrapped_id, id);
+            }
+        }
+    }
 
-    for (_package, ranges) in resolved.iter() {
-        for (_range, (version, is_root)) in ranges {
-            let mut dependencies = vec![];
+    for (package, ranges) in &resolved {
+        for (request, (version, _, dependency_scope)) in ranges {
+            let id = node_ids[&(package.to_owned(), request.to_owned())];
 
-            for dep in &version.dependencies {
-                if let Some(ranges) = resolved.get(dep.0) {
-                    if let Some((version, _)) = ranges.get(dep.1) {
-                        dependencies.push(version.to_owned())
-                    }
+            let dependencies = version
+                .dependencies
+                .iter()
+                .chain(&version.optional_dependencies);
+            for (dep_name, dep_range) in dependencies {
+                let dep_request = (dep_range.to_owned(), dependency_scope.to_owned());
+                if let Some(dep_id) = node_ids.get(&(dep_name.to_owned(), dep_request)) {
+                    graph.add_edge(id, *dep_id);
                 }
             }
-
-            resolved_deps.push(ResolvedDependencies::new(
-                version.to_owned(),
-                dependencies,
-                is_root.to_owned(),
-            ));
         }
     }
 
-    Ok(resolved_deps)
+    graph
+}
+
+/// A version range requested by the project or by one of the resolved packages.
+struct RequestedRange {
+    package_name: String,
+    range: VersionRangeSpecifier,
+    is_root: bool,
+    scope: Scope,
+}
+
+/// A requested version range and the version it resolved to.
+struct ResolvedRange {
+    range: VersionRangeSpecifier,
+    version: NpmPackageVersion,
+    is_root: bool,
+    scope: Scope,
+    /// The newest matching version, if it was held back by `minimum_release_age`.
+    newest_version: Option<Version>,
+    /// The versions pinned by the shrinkwrap of the package.
+    shrinkwrapped: Vec<NpmPackageVersion>,
 }
 
-pub fn construct_dependency_tree(
-    root_name: &String,
-    root_range: &VersionRangeSpecifier,
-    resolved_versions: &HashMap<String, HashMap<VersionRangeSpecifier, NpmPackageVersion>>,
-) -> anyhow::Result<ResolvedDependencyTree> {
-    let root_resolved_version = match resolved_versions.get(root_name) {
-        Some(versions) => versions.get(&root_range),
-        None => None,
+/// Resolve a version range of a package, unless a `locked` version is given.
+/// The result comes with the requested range it belongs to.
+async fn get_npm_package_version<F, Fut, S, SFut>(
+    fetch_package: &F,
+    fetch_shrinkwrap: &S,
+    locked: Option<LockedVersion>,
+    request: RequestedRange,
+    client: &Config,
+) -> (RequestKey, anyhow::Result<ResolvedRange>)
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = anyhow::Result<Arc<NpmResolvedPackage>>>,
+    S: Fn(NpmPackageVersion) -> SFut,
+    SFut: Future<Output = anyhow::Result<Option<Shrinkwrap>>>,
+{
+    let key = (
+        request.package_name.clone(),
+        request.range.clone(),
+        request.scope.clone(),
+    );
+    let resolved = resolve_range(fetch_package, fetch_shrinkwrap, locked, request, client).await;
+
+    (key, resolved)
+}
+
+async fn resolve_range<F, Fut, S, SFut>(
+    fetch_package: &F,
+    fetch_shrinkwrap: &S,
+    locked: Option<LockedVersion>,
+    request: RequestedRange,
+    client: &Config,
+) -> anyhow::Result<ResolvedRange>
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = anyhow::Result<Arc<NpmResolvedPackage>>>,
+    S: Fn(NpmPackageVersion) -> SFut,
+    SFut: Future<Output = anyhow::Result<Option<Shrinkwrap>>>,
+{
+    let RequestedRange {
+        package_name,
+        range: version_range,
+        is_root,
+        scope,
+    } = request;
+
+    if let Some((version, shrinkwrapped)) = locked {
+        return Ok(ResolvedRange {
+            range: version_range,
+            version,
+            is_root,
+            scope,
+            newest_version: None,
+            shrinkwrapped,
+        });
+    }
+
+    let (fetch_name, fetch_range) = match parse_jsr_spec(&package_name, &version_range)? {
+        Some(jsr) => jsr,
+        None => (package_name.clone(), version_range.clone()),
     };
+    let package = fetch_package(fetch_name.clone())
+        .await
+        .with_context(|| format!("failed to fetch {fetch_name}"))?;
 
-    let root_resolved_version = match root_resolved_version {
-        Some(version) => version.to_owned(),
-        None => {
-            return Err(Error::VersionDoesNotExist.into());
-        }
+    let options = client.resolve_options(&package_name, is_root);
+    let mut version = resolve_version_from_version_range(&package, &fetch_range, &options)?;
+    // JSR packages are linked under the name they were requested with.
+    version.name = package_name;
+    apply_package_extensions(&mut version, &client.package_extensions);
+
+    let shrinkwrap = if version.has_shrinkwrap {
+        fetch_shrinkwrap(version.clone()).await?
+    } else {
+        None
+    };
+    let shrinkwrapped = match shrinkwrap {
+        Some(shrinkwrap) => shrinkwrap.pin(&mut version),
+        None => vec![],
     };
 
-    let mut trees = Vec::new();
+    let newest_version = if options.published_before == client.before {
+        None
+    } else {
+        let options = ResolveOptions {
+            published_before: client.before,
+            ..options
+        };
+        resolve_version_from_version_range(&package, &fetch_range, &options)
+            .ok()
+            .map(|newest| newest.version)
+            .filter(|newest| *newest != version.version)
+    };
 
-    for (dep_name, dep_range) in &root_resolved_version.dependencies {
-        match construct_dependency_tree(dep_name, dep_range, resolved_versions) {
-            Ok(tree) => {
-                trees.push(tree);
-            }
-            Err(error) => return Err(error),
+    Ok(ResolvedRange {
+        range: version_range,
+        version,
+        is_root,
+        scope,
+        newest_version,
+        shrinkwrapped,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::hash_map::DefaultHasher,
+        hash::{Hash, Hasher},
+        time::Duration,
+    };
+
+    use serde_json::json;
+
+    use crate::{lockfile::Lockfile, npmrc::Npmrc, registry::MockRegistry};
+
+    use super::*;
+
+    fn packument(name: &str, versions: &[(&str, serde_json::Value)]) -> NpmResolvedPackage {
+        let latest = versions.last().unwrap().0;
+        let versions: serde_json::Map<String, serde_json::Value> = versions
+            .iter()
+            .map(|(version, dependencies)| {
+                (
+                    version.to_string(),
+                    json!({
+                        "name": name,
+                        "version": version,
+                        "dependencies": dependencies,
+                        "dist": {
+                            "shasum": "",
+                            "tarball": format!("https://registry.npmjs.org/{name}/-/{name}-{version}.tgz")
+                        }
+                    }),
+                )
+            })
+            .collect();
+
+        serde_json::from_value(json!({
+            "name": name,
+            "dist-tags": { "latest": latest },
+            "versions": versions,
+        }))
+        .unwrap()
+    }
+
+    fn packuments() -> HashMap<String, NpmResolvedPackage> {
+        [
+            packument(
+                "a",
+                &[(
+                    "1.0.0",
+                    json!({ "b": "^1.0.0", "c": "^1.0.0", "d": "1.0.0" }),
+                )],
+            ),
+            packument("b", &[("1.0.0", json!({ "c": "~1.0.0", "d": "^1.0.0" }))]),
+            packument(
+                "c",
+                &[
+                    ("1.0.0", json!({ "a": "^1.0.0" })),
+                    ("1.0.1", json!({ "a": "^1.0.0" })),
+                    ("1.1.0", json!({ "e": "*" })),
+                ],
+            ),
+            packument("d", &[("1.0.0", json!({})), ("1.2.0", json!({}))]),
+            packument("e", &[("1.0.0", json!({ "c": "^1.1.0" }))]),
+        ]
+        .into_iter()
+        .map(|package| (package.name.clone(), package))
+        .collect()
+    }
+
+    async fn resolve_shuffled(seed: u64) -> DependencyGraph {
+        let packuments = packuments();
+        let config = Config::default();
+
+        resolve_deps_with(
+            HashMap::from([
+                (
+                    String::from("a"),
+                    VersionRangeSpecifier::new(String::from("^1.0.0")),
+                ),
+                (
+                    String::from("d"),
+                    VersionRangeSpecifier::new(String::from("latest")),
+                ),
+            ]),
+            &LockedVersions::default(),
+            &config,
+            |package_name| {
+                let mut hasher = DefaultHasher::new();
+                (seed, &package_name).hash(&mut hasher);
+                let delay = Duration::from_millis(hasher.finish() % 20);
+                let package = packuments[&package_name].clone();
+
+                async move {
+                    tokio::time::sleep(delay).await;
+                    Ok(package)
+                }
+            },
+            |_| async { Ok(None) },
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn resolution_is_independent_of_fetch_order() {
+        let expected = resolve_shuffled(0).await;
+        let expected_lockfile =
+            serde_json::to_string(&Lockfile::new(&expected, &Config::default())).unwrap();
+
+        assert_eq!(
+            expected
+                .nodes()
+                .map(|(id, _)| expected.format_path(&[id]))
+                .collect::<Vec<_>>(),
+            vec!["a@1.0.0", "b@1.0.0", "c@1.1.0", "c@1.0.1", "d@1.0.0", "d@1.2.0", "e@1.0.0"]
+        );
+        assert_eq!(
+            expected
+                .cycles()
+                .iter()
+                .map(|cycle| expected.format_path(cycle))
+                .collect::<Vec<_>>(),
+            vec![
+                "a@1.0.0 -> b@1.0.0 -> c@1.0.1 -> a@1.0.0",
+                "c@1.1.0 -> e@1.0.0 -> c@1.1.0"
+            ]
+        );
+
+        for seed in 1..10 {
+            let resolved = resolve_shuffled(seed).await;
+            assert_eq!(resolved, expected);
+
+            let lockfile =
+                serde_json::to_string(&Lockfile::new(&resolved, &Config::default())).unwrap();
+            assert_eq!(lockfile, expected_lockfile);
         }
     }
 
-    let dep_tree = ResolvedDependencyTree::new(root_name.to_owned(), root_resolved_version, trees);
-    Ok(dep_tree)
-}
+    #[tokio::test]
+    async fn fetches_each_packument_once() {
+        let packuments = packuments();
+        let config = Config::default();
+        let fetched = std::sync::Mutex::new(vec![]);
 
-async fn get_npm_package_version(
-    package_name: String,
-    version_range: VersionRangeSpecifier,
-    is_root: bool,
-    client: &Config,
-) -> anyhow::Result<(VersionRangeSpecifier, NpmPackageVersion, bool)> {
-    let package = get_npm_package(&package_name, client).await?;
+        resolve_deps_with(
+            HashMap::from([(
+                String::from("a"),
+                VersionRangeSpecifier::new(String::from("^1.0.0")),
+            )]),
+            &LockedVersions::default(),
+            &config,
+            |package_name| {
+                fetched.lock().unwrap().push(package_name.clone());
+                let package = packuments[&package_name].clone();
+                async move {
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                    Ok(package)
+                }
+            },
+            |_| async { Ok(None) },
+        )
+        .await
+        .unwrap();
+
+        let mut fetched = fetched.into_inner().unwrap();
+        fetched.sort();
+        // `c` and `d` are requested with several ranges.
+        assert_eq!(fetched, vec!["a", "b", "c", "d", "e"]);
+    }
+
+    #[tokio::test]
+    async fn fetches_packuments_from_configured_registry() {
+        let packuments = packuments();
+        let mut registry = MockRegistry::new();
+        registry
+            .expect_get_package()
+            .withf(|name, preferred_registry, _| name == "d" && preferred_registry.is_none())
+            .times(1)
+            .returning(move |name, _, _| Ok(packuments[name].clone()));
+        let config = Config {
+            registry: Box::new(registry),
+            ..Config::default()
+        };
+
+        let graph = resolve_deps(
+            HashMap::from([(
+                String::from("d"),
+                VersionRangeSpecifier::new(String::from("^1.0.0")),
+            )]),
+            &LockedVersions::default(),
+            &config,
+        )
+        .await
+        .unwrap();
+
+        let (_, node) = graph.nodes().next().unwrap();
+        assert_eq!(node.version.version.as_str(), "1.2.0");
+    }
+
+    #[tokio::test]
+    async fn fetches_packuments_from_locked_registry() {
+        let packuments = packuments();
+        let mut registry = MockRegistry::new();
+        registry
+            .expect_get_package()
+            .withf(|name, preferred_registry, _| {
+                name == "d" && *preferred_registry == Some("https://registry.npmjs.org/")
+            })
+            .times(1)
+            .returning(move |name, _, _| Ok(packuments[name].clone()));
+        let config = Config {
+            npmrc: Npmrc::parse(
+                "registry=https://artifactory.example/\nregistry-fallbacks=https://registry.npmjs.org/",
+            ),
+            registry: Box::new(registry),
+            ..Config::default()
+        };
+
+        let mut graph = DependencyGraph::new();
+        graph.add_node(NpmPackageVersion::for_test("d", "1.0.0"), true);
+        let mut locked = Lockfile::new(&graph, &config).locked_versions(&config);
+        locked.unlock("d");
+
+        resolve_deps(
+            HashMap::from([(
+                String::from("d"),
+                VersionRangeSpecifier::new(String::from("^1.0.0")),
+            )]),
+            &locked,
+            &config,
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn reuses_locked_versions() {
+        let packuments = packuments();
+        let config = Config::default();
+        let fetched = std::sync::Mutex::new(vec![]);
+
+        let mut locked = LockedVersions::new([
+            packuments["a"].versions[0].clone(),
+            packuments["c"].versions[0].clone(),
+            packuments["d"].versions[0].clone(),
+        ]);
+        locked.unlock("a");
+
+        let graph = resolve_deps_with(
+            HashMap::from([
+                (
+                    String::from("a"),
+                    VersionRangeSpecifier::new(String::from("^1.0.0")),
+                ),
+                (
+                    String::from("d"),
+                    VersionRangeSpecifier::new(String::from("^1.0.0")),
+                ),
+            ]),
+            &locked,
+            &config,
+            |package_name| {
+                fetched.lock().unwrap().push(package_name.clone());
+                let package = packuments[&package_name].clone();
+                async move { Ok(package) }
+            },
+            |_| async { Ok(None) },
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            graph
+                .nodes()
+                .map(|(id, _)| graph.format_path(&[id]))
+                .collect::<Vec<_>>(),
+            vec!["a@1.0.0", "b@1.0.0", "c@1.0.0", "d@1.0.0"]
+        );
+
+        let mut fetched = fetched.into_inner().unwrap();
+        fetched.sort();
+        fetched.dedup();
+        // `c` and `d` are only requested with ranges their locked versions satisfy.
+        assert_eq!(fetched, vec!["a", "b"]);
+    }
 
-    let version =
-        resolve_version_from_version_range(&package, &version_range).map_err(|error| error)?;
+    #[test]
+    fn finds_lowest_locked_version_in_lowest_mode() {
+        let locked = LockedVersions::new(packuments()["d"].versions.values().cloned());
+        let range = VersionRangeSpecifier::new(String::from("^1.0.0"));
 
-    Ok((version_range.to_owned(), version, is_root))
+        assert_eq!(
+            locked.find("d", &range, false).unwrap().version.as_str(),
+            "1.2.0"
+        );
+        assert_eq!(
+            locked.find("d", &range, true).unwrap().version.as_str(),
+            "1.0.0"
+        );
+    }
+
+    #[tokio::test]
+    async fn upgrades_dependents_of_unlocked_packages() {
+        let packuments = packuments();
+        let config = Config::default();
+        let deps = HashMap::from([(
+            String::from("b"),
+            VersionRangeSpecifier::new(String::from("^1.0.0")),
+        )]);
+        let fetch = |fetched: &std::sync::Mutex<Vec<String>>, package_name: String| {
+            fetched.lock().unwrap().push(package_name.clone());
+            let package = packuments[&package_name].clone();
+            async move { Ok(package) }
+        };
+
+        let fetched = std::sync::Mutex::new(vec![]);
+        let graph = resolve_deps_with(
+            deps.clone(),
+            &LockedVersions::new([packuments["d"].versions[0].clone()]),
+            &config,
+            |package_name| fetch(&fetched, package_name),
+            |_| async { Ok(None) },
+        )
+        .await
+        .unwrap();
+        assert!(graph
+            .find("d", &Version::new(String::from("1.2.0")))
+            .is_none());
+
+        let mut locked = Lockfile::new(&graph, &config).locked_versions(&config);
+        locked.unlock("d");
+
+        let fetched = std::sync::Mutex::new(vec![]);
+        let graph = resolve_deps_with(
+            deps,
+            &locked,
+            &config,
+            |package_name| fetch(&fetched, package_name),
+            |_| async { Ok(None) },
+        )
+        .await
+        .unwrap();
+
+        // `b` stays locked, but its `^1.0.0` range now picks up the newer `d`,
+        // while `a` still requires exactly `d@1.0.0`.
+        assert_eq!(
+            graph
+                .nodes()
+                .map(|(id, _)| graph.format_path(&[id]))
+                .collect::<Vec<_>>(),
+            vec!["a@1.0.0", "b@1.0.0", "c@1.1.0", "c@1.0.1", "d@1.0.0", "d@1.2.0", "e@1.0.0"]
+        );
+        assert_eq!(fetched.into_inner().unwrap(), vec!["d"]);
+    }
+
+    /// The packuments with a `cli` package publishing [`cli_shrinkwrap`].
+    fn shrinkwrapped_packuments() -> HashMap<String, NpmResolvedPackage> {
+        let mut packuments = packuments();
+        packuments.insert(
+            String::from("cli"),
+            packument("cli", &[("1.0.0", json!({ "c": "^1.0.0" }))]),
+        );
+        packuments.get_mut("cli").unwrap().versions[0].has_shrinkwrap = true;
+
+        packuments
+    }
+
+    fn cli_shrinkwrap() -> Shrinkwrap {
+        serde_json::from_value(json!({
+            "lockfileVersion": 3,
+            "packages": {
+                "node_modules/c": {
+                    "version": "1.0.0",
+                    "resolved": "https://registry.npmjs.org/c/-/c-1.0.0.tgz",
+                    "dependencies": { "a": "^1.0.0" }
+                },
+                "node_modules/a": {
+                    "version": "1.0.0",
+                    "resolved": "https://registry.npmjs.org/a/-/a-1.0.0.tgz"
+                }
+            }
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn pins_shrinkwrapped_subtree() {
+        let packuments = shrinkwrapped_packuments();
+        let config = Config::default();
+        let fetched = std::sync::Mutex::new(vec![]);
+
+        let graph = resolve_deps_with(
+            HashMap::from([(
+                String::from("cli"),
+                VersionRangeSpecifier::new(String::from("^1.0.0")),
+            )]),
+            &LockedVersions::default(),
+            &config,
+            |package_name| {
+                fetched.lock().unwrap().push(package_name.clone());
+                let package = packuments[&package_name].clone();
+                async move { Ok(package) }
+            },
+            |version| async move {
+                assert_eq!(version.name, "cli");
+                Ok(Some(cli_shrinkwrap()))
+            },
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            graph
+                .nodes()
+                .map(|(id, _)| graph.format_path(&[id]))
+                .collect::<Vec<_>>(),
+            vec!["a@1.0.0", "c@1.0.0", "cli@1.0.0"]
+        );
+        // Only the shrinkwrapped package itself is fetched from the registry.
+        assert_eq!(fetched.into_inner().unwrap(), vec!["cli"]);
+    }
+
+    #[tokio::test]
+    async fn reuses_locked_shrinkwrap_pins() {
+        let packuments = shrinkwrapped_packuments();
+        let config = Config::default();
+        let deps = HashMap::from([(
+            String::from("cli"),
+            VersionRangeSpecifier::new(String::from("^1.0.0")),
+        )]);
+
+        let graph = resolve_deps_with(
+            deps.clone(),
+            &LockedVersions::default(),
+            &config,
+            |package_name| {
+                let package = packuments[&package_name].clone();
+                async move { Ok(package) }
+            },
+            |_| async { Ok(Some(cli_shrinkwrap())) },
+        )
+        .await
+        .unwrap();
+        let lockfile = Lockfile::new(&graph, &config);
+        assert_eq!(
+            lockfile.packages["cli@1.0.0"].shrinkwrapped,
+            vec!["a@1.0.0", "c@1.0.0"]
+        );
+
+        let relocked = resolve_deps_with(
+            deps,
+            &lockfile.locked_versions(&config),
+            &config,
+            |package_name| async move { panic!("fetched {package_name}") },
+            |version| async move { panic!("fetched the shrinkwrap of {}", version.name) },
+        )
+        .await
+        .unwrap();
+
+        // The locked `cli` keeps its dependencies scoped to its shrinkwrap.
+        assert_eq!(Lockfile::new(&relocked, &config), lockfile);
+    }
+
+    #[tokio::test]
+    async fn scopes_shrinkwrap_pins_to_their_subtree() {
+        let mut packuments = packuments();
+        packuments.insert(
+            String::from("cli"),
+            packument("cli", &[("1.0.0", json!({ "d": "^1.0.0" }))]),
+        );
+        packuments.get_mut("cli").unwrap().versions[0].has_shrinkwrap = true;
+        packuments.insert(
+            String::from("x"),
+            packument("x", &[("1.0.0", json!({ "d": "1.0.0" }))]),
+        );
+        let config = Config::default();
+        let fetched = std::sync::Mutex::new(vec![]);
+
+        let graph = resolve_deps_with(
+            HashMap::from([
+                (
+                    String::from("cli"),
+                    VersionRangeSpecifier::new(String::from("^1.0.0")),
+                ),
+                (
+                    String::from("x"),
+                    VersionRangeSpecifier::new(String::from("^1.0.0")),
+                ),
+            ]),
+            &LockedVersions::default(),
+            &config,
+            |package_name| {
+                fetched.lock().unwrap().push(package_name.clone());
+                // `x` requests the pinned `d@1.0.0` after the shrinkwrap of `cli` is read.
+                let delay = if package_name == "x" { 20 } else { 0 };
+                let package = packuments[&package_name].clone();
+                async move {
+                    tokio::time::sleep(Duration::from_millis(delay)).await;
+                    Ok(package)
+                }
+            },
+            |_| async move {
+                Ok(Some(
+                    serde_json::from_value(json!({
+                        "lockfileVersion": 3,
+                        "packages": {
+                            "node_modules/d": {
+                                "version": "1.0.0",
+                                "resolved": "https://registry.npmjs.org/d/-/d-1.0.0.tgz"
+                            }
+                        }
+                    }))
+                    .unwrap(),
+                ))
+            },
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            graph
+                .nodes()
+                .map(|(id, _)| graph.format_path(&[id]))
+                .collect::<Vec<_>>(),
+            vec!["cli@1.0.0", "d@1.0.0", "x@1.0.0"]
+        );
+        // Only `cli` gets `d` from its shrinkwrap, `x` resolves it from the registry.
+        let mut fetched = fetched.into_inner().unwrap();
+        fetched.sort();
+        assert_eq!(fetched, vec!["cli", "d", "x"]);
+    }
+
+    #[tokio::test]
+    async fn skips_optional_dependencies_that_fail_to_resolve() {
+        let packuments = packuments();
+        let resolve = |extensions: serde_json::Value| {
+            let packuments = &packuments;
+            async move {
+                let config = Config {
+                    package_extensions: serde_json::from_value(extensions).unwrap(),
+                    ..Config::default()
+                };
+
+                resolve_deps_with(
+                    HashMap::from([(
+                        String::from("b"),
+                        VersionRangeSpecifier::new(String::from("^1.0.0")),
+                    )]),
+                    &LockedVersions::default(),
+                    &config,
+                    |package_name| {
+                        let package = packuments.get(&package_name).cloned();
+                        async move { package.context("not found") }
+                    },
+                    |_| async { Ok(None) },
+                )
+                .await
+            }
+        };
+
+        let graph = resolve(json!({
+            "b": { "optionalDependencies": { "missing": "^1.0.0", "d": "^1.0.0" } }
+        }))
+        .await
+        .unwrap();
+        let b = graph
+            .find("b", &Version::new(String::from("1.0.0")))
+            .unwrap();
+        assert_eq!(
+            graph
+                .node(b)
+                .dependencies
+                .iter()
+                .map(|id| graph.format_path(&[*id]))
+                .collect::<Vec<_>>(),
+            vec!["c@1.0.1", "d@1.2.0"]
+        );
+
+        // A range that is also required still fails the install.
+        let error = resolve(json!({
+            "b": { "optionalDependencies": { "missing": "^1.0.0" } },
+            "d": { "dependencies": { "missing": "^1.0.0" } }
+        }))
+        .await
+        .unwrap_err();
+        assert_eq!(error.to_string(), "failed to fetch missing");
+    }
+
+    #[tokio::test]
+    async fn resolves_jsr_specs() {
+        let packuments = HashMap::from([(
+            String::from("@jsr/std__path"),
+            packument(
+                "@jsr/std__path",
+                &[("1.0.0", json!({})), ("1.1.0", json!({}))],
+            ),
+        )]);
+        let config = Config::default();
+
+        let graph = resolve_deps_with(
+            HashMap::from([(
+                String::from("@std/path"),
+                VersionRangeSpecifier::new(String::from("jsr:~1.0.0")),
+            )]),
+            &LockedVersions::default(),
+            &config,
+            |package_name| {
+                let package = packuments[&package_name].clone();
+                async move { Ok(package) }
+            },
+            |_| async { Ok(None) },
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            graph
+                .nodes()
+                .map(|(id, _)| graph.format_path(&[id]))
+                .collect::<Vec<_>>(),
+            vec!["@std/path@1.0.0"]
+        );
+    }
 }
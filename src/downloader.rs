@@ -1,151 +1,227 @@
 use std::{
     collections::HashSet,
     error,
-    io::ErrorKind,
+    io::{self, ErrorKind, Read},
     path::{Path, PathBuf},
 };
 
-use async_compression::tokio::bufread::GzipDecoder;
 use derive_more::Display;
-use futures::{future::join_all, TryStreamExt};
+use flate2::read::GzDecoder;
+use futures::future::join_all;
 use tar::Archive;
-use tokio::{
-    fs,
-    io::{self, BufReader},
-    task,
-};
-use tokio_util::{compat::FuturesAsyncReadCompatExt, io::SyncIoBridge};
+use tokio::{fs, task};
 
 use crate::{
+    cacache::IntegrityReader,
     config::Config,
-    http::get_package_tar,
-    npm::{ResolvedDependencies, UrlString, Version},
+    dependency_graph::DependencyGraph,
+    npm::{UrlString, Version},
     STORE_FOLDER,
 };
 
 #[derive(Debug, Display, PartialEq)]
 pub enum Error {
-    UnpackError,
+    #[display(fmt = "failed to unpack {}", _0)]
+    UnpackError(UrlString),
+    #[display(fmt = "{} does not match its integrity", _0)]
+    IntegrityMismatch(UrlString),
 }
 
 impl error::Error for Error {}
 
 /// download packages to store.
-/// returns the top level package, if specified.
-pub async fn download_packages(
-    packages: &Vec<ResolvedDependencies>,
-    config: &Config,
-) -> anyhow::Result<Vec<ResolvedDependencies>> {
-    let mut top_level = vec![];
-
+pub async fn download_packages(graph: &DependencyGraph, config: &Config) -> anyhow::Result<()> {
     let mut futures = Vec::new();
-    let mut downloaded = HashSet::new();
-    for dep in packages.iter() {
-        if downloaded.contains(&dep.version.dist.tarball) {
-            continue;
-        }
+    for id in graph.topological_order() {
+        let version = &graph.node(id).version;
 
-        if dep.is_root {
-            top_level.push(dep.to_owned());
+        if get_store_package_path(&version.name, &version.version).exists() {
+            continue;
         }
 
         futures.push(download_package_to_store(
-            dep.version.name.clone(),
-            dep.version.version.clone(),
-            dep.version.dist.tarball.clone(),
+            version.name.clone(),
+            version.version.clone(),
+            version.dist.tarball.clone(),
+            version.dist.integrity.clone(),
             config,
         ));
+    }
+    let results = join_all(futures).await;
+    let errors: Vec<_> = results.into_iter().filter_map(Result::err).collect();
+    for error in &errors {
+        println!("download to store: {error:#}");
+    }
 
-        downloaded.insert(&dep.version.dist.tarball);
+    match errors.into_iter().next() {
+        Some(error) => Err(error),
+        None => Ok(()),
     }
-    let _results = join_all(futures).await;
-    println!("download to store: ");
-    println!(
-        "{:?}",
-        _results
-            .iter()
-            .filter(|res| res.is_err())
-            .collect::<Vec<_>>()
-    );
-
-    Ok(top_level)
 }
 
-/// download a single package to store.
+/// download a single package to store, unless the tarball cache or npm's cache already has it.
+/// Tarballs are streamed from disk through their integrity check into the store,
+/// so they are never held in memory.
 pub async fn download_package_to_store(
     package_name: String,
     version: Version,
     tar: UrlString,
+    integrity: Option<String>,
     config: &Config,
 ) -> anyhow::Result<()> {
-    let tar_content = get_package_tar(&tar, config).await.unwrap();
-
     let deps_dest = get_store_package_path(&package_name, &version);
 
-    let tgz = GzipDecoder::new(
-        tar_content
-            .bytes_stream()
-            .map_err(|e| io::Error::new(ErrorKind::Other, e))
-            .into_async_read()
-            .compat(),
-    );
+    let cached = match config
+        .tarball_cache
+        .find_tarball(integrity.as_deref(), &tar)
+        .await
+    {
+        Some(cached) => Some(cached),
+        None => {
+            config
+                .npm_cache
+                .find_tarball(integrity.as_deref(), &tar)
+                .await
+        }
+    };
+
+    if let Some((cached_path, cached_integrity)) = cached {
+        match unpack_to_store(&cached_path, Some(cached_integrity), &tar, &deps_dest).await {
+            Ok(integrity) => {
+                keep_tarball(&cached_path, &integrity, &tar, config).await;
+                return Ok(());
+            }
+            // A corrupt cache entry is downloaded again.
+            Err(error) if is_corrupt(&error) => {}
+            Err(error) => return Err(error),
+        }
+    }
 
-    fs::create_dir_all(&deps_dest).await.unwrap();
+    let partial_path = fetch_tarball(&package_name, &version, &tar, config).await?;
+    let unpacked = unpack_to_store(&partial_path, integrity, &tar, &deps_dest).await;
+    if let Ok(integrity) = &unpacked {
+        keep_tarball(&partial_path, integrity, &tar, config).await;
+    }
+    // A corrupt tarball isn't resumed either.
+    fs::remove_file(&partial_path).await?;
 
-    let mut extracted = HashSet::new();
+    unpacked.map(|_| ())
+}
+
+/// Add the tarball to the tarball cache. The package is installed either way,
+/// so failing to cache it is only a warning.
+async fn keep_tarball(tarball: &Path, integrity: &str, tar: &UrlString, config: &Config) {
+    if let Err(error) = config
+        .tarball_cache
+        .write_tarball(tarball, integrity, tar)
+        .await
+    {
+        eprintln!("warning: failed to keep the tarball of {tar}: {error}");
+    }
+}
+
+/// Download the tarball to its partial download path, which is returned.
+async fn fetch_tarball(
+    package_name: &String,
+    version: &Version,
+    tar: &UrlString,
+    config: &Config,
+) -> anyhow::Result<PathBuf> {
+    let partial_path = get_partial_tarball_path(package_name, version);
+    if let Some(parent) = partial_path.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+
+    let _permit = config.network_concurrency.tarballs.acquire().await?;
+    config
+        .registry
+        .download_tarball(tar, &partial_path, config)
+        .await?;
+
+    Ok(partial_path)
+}
+
+/// Whether `error` means the tarball itself is bad, so it should be fetched again.
+fn is_corrupt(error: &anyhow::Error) -> bool {
+    matches!(
+        error.downcast_ref(),
+        Some(Error::IntegrityMismatch(_) | Error::UnpackError(_))
+    )
+}
+
+/// Extract the tarball at `tarball` to `deps_dest`, checking it against `integrity`
+/// while it's read. Returns the integrity of the tarball.
+/// The tarball is extracted next to `deps_dest` and only moved into place once it matches,
+/// so `deps_dest` never holds a partial package.
+async fn unpack_to_store(
+    tarball: &Path,
+    integrity: Option<String>,
+    tar: &UrlString,
+    deps_dest: &Path,
+) -> anyhow::Result<String> {
+    let tarball = tarball.to_path_buf();
+    let tar = tar.to_owned();
+    let deps_dest = deps_dest.to_path_buf();
 
     task::spawn_blocking(move || {
-        let mut archive = Archive::new(SyncIoBridge::new(BufReader::new(tgz)));
-        let mut entries = archive.entries().unwrap();
-        while let Some(file) = &mut entries.next() {
-            match file {
-                Ok(file) => {
-                    let file_path = file.path().unwrap();
-                    let file_path = match file_path.strip_prefix("package") {
-                        Ok(path) => path.to_path_buf(),
-                        Err(_) => file_path.to_path_buf(),
-                    };
-
-                    if extracted.contains(&file_path) {
-                        continue;
-                    }
-
-                    extracted.insert(file_path.to_owned());
-
-                    if let Some(parent) = file_path.parent() {
-                        match std::fs::create_dir_all(deps_dest.join(parent)) {
-                            Err(error) if error.kind() == ErrorKind::AlreadyExists => {}
-                            Err(error) => return Err(error),
-                            _ => {}
-                        }
-                    }
-
-                    let dst = deps_dest.join(file_path);
-                    match file.unpack(dst) {
-                        Err(error) => {
-                            let formatted = format!("{:?}", error);
-                            println!("{formatted}");
-                            panic!("{:?}", error);
-                        }
-                        Ok(_) => {}
-                    }
-                }
-                Err(error) => {
-                    println!(
-                        "{}, {}, {}, {}",
-                        &package_name,
-                        &version,
-                        &tar,
-                        &deps_dest.to_str().unwrap_or("failed deps_dest"),
-                    );
-                    panic!("{:?}", error);
-                }
-            }
+        let parent = deps_dest.parent().unwrap_or(Path::new("."));
+        std::fs::create_dir_all(parent)?;
+        let extracting = tempfile::Builder::new()
+            .prefix(".extracting-")
+            .tempdir_in(parent)?;
+
+        let mut reader = IntegrityReader::new(std::fs::File::open(&tarball)?, integrity.as_deref());
+        // The archive ends before the gzip trailer, which is part of the integrity too.
+        let extracted = extract(&mut reader, extracting.path())
+            .and_then(|()| io::copy(&mut reader, &mut io::sink()));
+
+        let Some(integrity) = reader.verify() else {
+            return Err(Error::IntegrityMismatch(tar).into());
+        };
+        if let Err(error) = extracted {
+            return Err(anyhow::Error::new(error).context(Error::UnpackError(tar)));
         }
 
-        Ok(())
+        match std::fs::rename(extracting.path(), &deps_dest) {
+            // Another install of the same package got there first.
+            Err(_) if deps_dest.exists() => {}
+            Err(error) => return Err(error.into()),
+            Ok(()) => {}
+        }
+
+        Ok(integrity)
     })
-    .await??;
+    .await?
+}
+
+fn extract(tgz: impl Read, deps_dest: &Path) -> io::Result<()> {
+    let mut extracted = HashSet::new();
+
+    let mut archive = Archive::new(GzDecoder::new(tgz));
+    for file in archive.entries()? {
+        let mut file = file?;
+        let file_path = file.path()?;
+        let file_path = match file_path.strip_prefix("package") {
+            Ok(path) => path.to_path_buf(),
+            Err(_) => file_path.to_path_buf(),
+        };
+
+        if extracted.contains(&file_path) {
+            continue;
+        }
+
+        extracted.insert(file_path.to_owned());
+
+        if let Some(parent) = file_path.parent() {
+            match std::fs::create_dir_all(deps_dest.join(parent)) {
+                Err(error) if error.kind() == ErrorKind::AlreadyExists => {}
+                Err(error) => return Err(error),
+                _ => {}
+            }
+        }
+
+        file.unpack(deps_dest.join(file_path))?;
+    }
 
     Ok(())
 }
@@ -153,3 +229,70 @@ pub async fn download_package_to_store(
 pub fn get_store_package_path(package_name: &String, version: &Version) -> PathBuf {
     Path::new(STORE_FOLDER).join(format!("{}@{}", &package_name, &version))
 }
+
+/// Where the tarball is downloaded to before it's extracted, kept across runs to resume from.
+fn get_partial_tarball_path(package_name: &String, version: &Version) -> PathBuf {
+    Path::new(STORE_FOLDER).join(format!("{}@{}.tgz.partial", &package_name, &version))
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use flate2::{write::GzEncoder, Compression};
+    use sha2::{Digest, Sha512};
+
+    use super::*;
+
+    fn tarball() -> Vec<u8> {
+        let mut builder = tar::Builder::new(GzEncoder::new(vec![], Compression::default()));
+        let content = br#"{ "name": "a" }"#;
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        builder
+            .append_data(&mut header, "package/package.json", &content[..])
+            .unwrap();
+
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    #[tokio::test]
+    async fn unpacks_tarballs_matching_their_integrity() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a-1.0.0.tgz");
+        std::fs::write(&path, tarball()).unwrap();
+        let integrity = format!("sha512-{}", STANDARD.encode(Sha512::digest(tarball())));
+        let tar = UrlString::new(String::from("https://registry.npmjs.org/a/-/a-1.0.0.tgz"));
+        let dest = dir.path().join("a@1.0.0");
+
+        let unpacked = unpack_to_store(&path, Some(integrity.clone()), &tar, &dest).await;
+        assert_eq!(unpacked.unwrap(), integrity);
+        assert!(dest.join("package.json").exists());
+
+        let dest = dir.path().join("b@1.0.0");
+        let error = unpack_to_store(&path, Some(String::from("sha512-AAAA")), &tar, &dest)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.downcast_ref(),
+            Some(&Error::IntegrityMismatch(tar.clone()))
+        );
+        assert!(!dest.exists());
+    }
+
+    #[tokio::test]
+    async fn fails_to_unpack_corrupt_tarballs() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a-1.0.0.tgz");
+        std::fs::write(&path, &tarball()[..40]).unwrap();
+        let tar = UrlString::new(String::from("https://registry.npmjs.org/a/-/a-1.0.0.tgz"));
+        let dest = dir.path().join("a@1.0.0");
+
+        let error = unpack_to_store(&path, None, &tar, &dest).await.unwrap_err();
+
+        assert!(is_corrupt(&error));
+        assert_eq!(error.to_string(), format!("failed to unpack {tar}"));
+        // Only the truncated tarball is left, without a partial package.
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
@@ -1,60 +1,1033 @@
-use std::str::FromStr;
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
 
+use chrono::Utc;
 use derive_more::Display;
+use futures::StreamExt;
+use rand::Rng;
+use reqwest::StatusCode;
+use tokio::{
+    fs::{self, OpenOptions},
+    io::AsyncWriteExt,
+    sync::Semaphore,
+};
 
 use crate::{
     config::Config,
+    metadata_cache::CachedPackument,
     npm::{NpmResolvedPackage, UrlString},
+    npmrc::Npmrc,
 };
 
-const NPM_REGISTRY_URL: &str = "registry.npmjs.org/";
 const INSTALL_FETCH_HEADER: &str =
     "application/vnd.npm.install-v1+json; q=1.0, application/json; q=0.8, */*";
+const FULL_FETCH_HEADER: &str = "application/json";
+const DEFAULT_NETWORK_CONCURRENCY: usize = 16;
 
 #[derive(Debug, Display, derive_more::Error)]
 pub enum Error {
-    HttpError,
+    #[display(fmt = "{} responded with {}", _0, _1)]
+    Status(
+        #[error(not(source))] String,
+        #[error(not(source))] StatusCode,
+    ),
+    #[display(fmt = "request to {} failed: {}", _0, _1)]
+    Request(
+        #[error(not(source))] String,
+        #[error(source)] reqwest::Error,
+    ),
+    #[display(fmt = "{} is not cached and --offline forbids fetching it", _0)]
+    Offline(#[error(not(source))] String),
+}
+
+/// Whether the network may be used.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum NetworkMode {
+    /// Revalidate stale packuments and download missing tarballs.
+    #[default]
+    Online,
+    /// Use cached packuments even if stale, and only fetch the missing ones.
+    PreferOffline,
+    /// Never use the network, failing when a packument or tarball isn't cached.
+    Offline,
+}
+
+impl NetworkMode {
+    pub fn from_npmrc(npmrc: &Npmrc) -> Self {
+        if npmrc.get("offline") == Some("true") {
+            Self::Offline
+        } else if npmrc.get("prefer-offline") == Some("true") {
+            Self::PreferOffline
+        } else {
+            Self::Online
+        }
+    }
+}
+
+/// How failed requests are retried, configured like npm with `fetch-retries`,
+/// `fetch-retry-factor`, `fetch-retry-mintimeout`, `fetch-retry-maxtimeout` and `fetch-timeout`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub retries: u32,
+    pub factor: u32,
+    pub min_timeout: Duration,
+    pub max_timeout: Duration,
+    /// The timeout of a single attempt.
+    pub timeout: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            retries: 2,
+            factor: 10,
+            min_timeout: Duration::from_secs(10),
+            max_timeout: Duration::from_secs(60),
+            timeout: Duration::from_secs(5 * 60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn from_npmrc(npmrc: &Npmrc) -> anyhow::Result<Self> {
+        let mut policy = Self::default();
+        let millis = |value: &str| value.parse().map(Duration::from_millis);
+
+        if let Some(retries) = npmrc.get("fetch-retries") {
+            policy.retries = retries.parse()?;
+        }
+        if let Some(factor) = npmrc.get("fetch-retry-factor") {
+            policy.factor = factor.parse()?;
+        }
+        if let Some(min_timeout) = npmrc.get("fetch-retry-mintimeout") {
+            policy.min_timeout = millis(min_timeout)?;
+        }
+        if let Some(max_timeout) = npmrc.get("fetch-retry-maxtimeout") {
+            policy.max_timeout = millis(max_timeout)?;
+        }
+        if let Some(timeout) = npmrc.get("fetch-timeout") {
+            policy.timeout = millis(timeout)?;
+        }
+
+        Ok(policy)
+    }
+
+    /// The delay before retrying for the `attempt`th time, starting at 0.
+    /// Grows exponentially up to `max_timeout`, with jitter so clients don't retry in lockstep.
+    pub(crate) fn backoff(&self, attempt: u32) -> Duration {
+        let delay = self
+            .min_timeout
+            .saturating_mul(self.factor.saturating_pow(attempt))
+            .min(self.max_timeout);
+
+        delay.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    }
+}
+
+/// Limits on concurrent requests, set with `network-concurrency`.
+/// The limit is split between packument and tarball requests, so that downloads
+/// can't starve the packument lookups resolution is waiting on.
+#[derive(Debug)]
+pub struct NetworkConcurrency {
+    pub metadata: Semaphore,
+    pub tarballs: Semaphore,
 }
 
+impl Default for NetworkConcurrency {
+    fn default() -> Self {
+        Self::new(DEFAULT_NETWORK_CONCURRENCY)
+    }
+}
+
+impl NetworkConcurrency {
+    /// Give packuments half of `limit` rounded up and tarballs the rest, at least one each.
+    pub fn new(limit: usize) -> Self {
+        let metadata = limit.div_ceil(2).max(1);
+        let tarballs = (limit - metadata.min(limit)).max(1);
+
+        Self {
+            metadata: Semaphore::new(metadata),
+            tarballs: Semaphore::new(tarballs),
+        }
+    }
+
+    pub fn from_npmrc(npmrc: &Npmrc) -> anyhow::Result<Self> {
+        match npmrc.get("network-concurrency") {
+            Some(limit) => Ok(Self::new(limit.parse()?)),
+            None => Ok(Self::default()),
+        }
+    }
+}
+
+/// Fetch the packument of `name` from its registries in order, falling through to the next
+/// one when a registry doesn't have the package, can't be reached or keeps failing.
 pub async fn get_npm_package(name: &String, config: &Config) -> anyhow::Result<NpmResolvedPackage> {
-    let package_url =
-        reqwest::Url::from_str(format!("https://{NPM_REGISTRY_URL}").as_str())?.join(name)?;
-
-    let response = match config
-        .client
-        .get(package_url.clone())
-        .header(reqwest::header::ACCEPT, INSTALL_FETCH_HEADER)
-        .send()
+    get_npm_package_from_registries(name, config.registry_urls(name), config).await
+}
+
+/// Like [`get_npm_package`], but going straight to `registry` when it's one of the registries
+/// of `name`, such as the registry that served the package before.
+/// The other registries are only tried if it doesn't have the package anymore.
+pub async fn get_npm_package_preferring(
+    name: &String,
+    registry: &str,
+    config: &Config,
+) -> anyhow::Result<NpmResolvedPackage> {
+    let mut registries = config.registry_urls(name);
+    if let Some(index) = registries.iter().position(|url| url == registry) {
+        let preferred = registries.remove(index);
+        registries.insert(0, preferred);
+    }
+
+    get_npm_package_from_registries(name, registries, config).await
+}
+
+async fn get_npm_package_from_registries(
+    name: &String,
+    registries: Vec<String>,
+    config: &Config,
+) -> anyhow::Result<NpmResolvedPackage> {
+    let (last, fallbacks) = registries
+        .split_last()
+        .expect("every package has a registry");
+
+    for registry in fallbacks {
+        match get_npm_package_from(name, registry, config).await {
+            Err(error) if falls_through(&error) => {
+                eprintln!("{error:#}, trying the next registry");
+            }
+            result => return result,
+        }
+    }
+
+    get_npm_package_from(name, last, config).await
+}
+
+/// Whether the next registry should be tried after `error`.
+fn falls_through(error: &anyhow::Error) -> bool {
+    match error.downcast_ref() {
+        Some(Error::Status(_, status)) => {
+            *status == StatusCode::NOT_FOUND || status.is_server_error()
+        }
+        Some(Error::Request(_, _) | Error::Offline(_)) => true,
+        _ => false,
+    }
+}
+
+/// Fetch the packument of `name` from `registry`, pointing its tarballs at `registry`.
+async fn get_npm_package_from(
+    name: &String,
+    registry: &str,
+    config: &Config,
+) -> anyhow::Result<NpmResolvedPackage> {
+    let package_url = reqwest::Url::from_str(registry)?.join(name)?;
+
+    let full = config.needs_publish_times();
+    let accept = if full {
+        FULL_FETCH_HEADER
+    } else {
+        INSTALL_FETCH_HEADER
+    };
+
+    let cached = config.metadata_cache.read(&package_url, full).await;
+    match (&cached, config.network_mode) {
+        (Some(cached), NetworkMode::Offline | NetworkMode::PreferOffline) => {
+            return parse_packument(name, &package_url, &cached.body, registry, config);
+        }
+        (Some(cached), NetworkMode::Online) if config.metadata_cache.is_fresh(cached) => {
+            return parse_packument(name, &package_url, &cached.body, registry, config);
+        }
+        (None, NetworkMode::Offline) => {
+            return Err(Error::Offline(package_url.to_string()).into());
+        }
+        _ => {}
+    }
+
+    let authorization = config.npmrc.authorization(&package_url)?;
+    let _permit = config.network_concurrency.metadata.acquire().await?;
+    let response = send_with_retry(&package_url, config, || {
+        let mut request = authorize(config.client.get(package_url.clone()), &authorization)
+            .header(reqwest::header::ACCEPT, accept);
+
+        if let Some(cached) = &cached {
+            if let Some(etag) = &cached.headers.etag {
+                request = request.header(reqwest::header::IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = &cached.headers.last_modified {
+                request = request.header(reqwest::header::IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+
+        request
+    })
+    .await?;
+
+    let packument = match (response.status(), cached) {
+        (StatusCode::NOT_MODIFIED, Some(mut cached)) => {
+            cached.headers.fetched_at = Utc::now();
+            cached
+        }
+        // Nothing was cached to revalidate, so the registry shouldn't have answered this.
+        (StatusCode::NOT_MODIFIED, None) => {
+            return Err(Error::Status(package_url.to_string(), StatusCode::NOT_MODIFIED).into());
+        }
+        _ => {
+            let headers = response.headers().clone();
+            match response.text().await {
+                Ok(body) => CachedPackument::from_response(&headers, body),
+                Err(error) => {
+                    println!("TEXT: {name}, {package_url}, {:?}", error,);
+                    return Err(error.into());
+                }
+            }
+        }
+    };
+
+    // Only packuments that parse are cached, so a broken response isn't served again.
+    let parsed = parse_packument(name, &package_url, &packument.body, registry, config)?;
+
+    if let Err(error) = config
+        .metadata_cache
+        .write(&package_url, full, &packument)
         .await
     {
-        Ok(response) => response,
+        eprintln!("warning: failed to cache the packument of {name}: {error}");
+    }
+
+    Ok(parsed)
+}
+
+fn parse_packument(
+    name: &str,
+    package_url: &reqwest::Url,
+    body: &str,
+    registry: &str,
+    config: &Config,
+) -> anyhow::Result<NpmResolvedPackage> {
+    match serde_json::from_str::<NpmResolvedPackage>(body) {
+        Ok(mut json) => {
+            for version in json.versions.values_mut() {
+                version.dist.tarball =
+                    UrlString::new(config.served_tarball_url(&version.dist.tarball, registry));
+            }
+
+            Ok(json)
+        }
         Err(error) => {
-            println!("Fetch: {name}, {package_url}, {:?}", error);
-            return Err(Error::HttpError.into());
+            println!("JSON: {name}, {package_url}, {:?}", error,);
+            Err(error.into())
         }
+    }
+}
+
+/// Request the tarball from where `tarball_url` points to, starting at byte `offset` with
+/// a `Range` header when it isn't 0. The range only applies while the tarball still matches
+/// `if_range`, its `ETag` or `Last-Modified` from when the download started.
+/// Servers without range support, or whose tarball changed, answer `200 OK` with the whole
+/// tarball instead of `206`. Callers must hold a `network_concurrency.tarballs` permit
+/// until they are done reading the body.
+pub async fn get_package_tar(
+    tarball: &UrlString,
+    offset: u64,
+    if_range: Option<&str>,
+    config: &Config,
+) -> anyhow::Result<reqwest::Response> {
+    if config.network_mode == NetworkMode::Offline {
+        return Err(Error::Offline(tarball.to_string()).into());
+    }
+
+    let url = reqwest::Url::parse(&config.tarball_url(tarball))?;
+    let authorization = config.npmrc.authorization(&url)?;
+
+    Ok(send_with_retry(&url, config, || {
+        let mut request = authorize(config.client.get(url.clone()), &authorization);
+        if offset > 0 {
+            request = request.header(reqwest::header::RANGE, format!("bytes={offset}-"));
+        }
+        if let Some(if_range) = if_range {
+            request = request.header(reqwest::header::IF_RANGE, if_range);
+        }
+
+        request
+    })
+    .await?)
+}
+
+/// Download the tarball to `path`. When the connection drops mid-download, what was
+/// received so far is kept in `path` and the rest is requested with a `Range` header,
+/// so a previous partial download is resumed as well.
+pub async fn download_tarball(
+    tarball: &UrlString,
+    path: &Path,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let mut attempt = 0;
+
+    loop {
+        match download_tarball_rest(tarball, path, config).await {
+            Err(error) if error.is::<reqwest::Error>() && attempt < config.retry_policy.retries => {
+                tokio::time::sleep(config.retry_policy.backoff(attempt)).await;
+                attempt += 1;
+            }
+            Err(error) => return Err(error),
+            Ok(()) => {
+                // The download is complete, so it won't be resumed.
+                return match fs::remove_file(validator_path(path)).await {
+                    Err(error) if error.kind() != ErrorKind::NotFound => Err(error.into()),
+                    _ => Ok(()),
+                };
+            }
+        }
+    }
+}
+
+/// Append the bytes of the tarball missing from `path` to it.
+/// Only downloads whose validator was recorded are resumed, the others start over.
+async fn download_tarball_rest(
+    tarball: &UrlString,
+    path: &Path,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let offset = fs::metadata(path)
+        .await
+        .map_or(0, |metadata| metadata.len());
+    let validator = match offset {
+        0 => None,
+        _ => fs::read_to_string(validator_path(path)).await.ok(),
     };
 
-    let text = match response.text().await {
-        Ok(response) => match serde_json::from_str(response.as_str()) {
-            Ok(json) => Ok(json),
-            Err(error) => {
-                println!("{:?}", response);
-                println!("JSON: {name}, {package_url}, {:?}", error,);
-                return Err(error.into());
+    let response = match validator {
+        Some(validator) => {
+            match get_package_tar(tarball, offset, Some(&validator), config).await {
+                // The partial download doesn't belong to the current tarball, start over.
+                Err(error)
+                    if matches!(
+                        error.downcast_ref(),
+                        Some(Error::Status(_, StatusCode::RANGE_NOT_SATISFIABLE))
+                    ) =>
+                {
+                    None
+                }
+                Ok(response)
+                    if response.status() == StatusCode::PARTIAL_CONTENT
+                        && content_range_start(&response) != Some(offset) =>
+                {
+                    None
+                }
+                result => Some(result?),
             }
-        },
-        Err(error) => {
-            println!("TEXT: {name}, {package_url}, {:?}", error,);
-            return Err(error.into());
         }
+        None => None,
+    };
+    let response = match response {
+        Some(response) => response,
+        None => get_package_tar(tarball, 0, None, config).await?,
     };
 
-    text
+    let mut file = if response.status() == StatusCode::PARTIAL_CONTENT {
+        OpenOptions::new().append(true).open(path).await?
+    } else {
+        // Remember which version of the tarball this is, to resume it later.
+        match response_validator(&response) {
+            Some(validator) => fs::write(validator_path(path), validator).await?,
+            None => match fs::remove_file(validator_path(path)).await {
+                Err(error) if error.kind() != ErrorKind::NotFound => return Err(error.into()),
+                _ => {}
+            },
+        }
+
+        fs::File::create(path).await?
+    };
+
+    let mut body = response.bytes_stream();
+    while let Some(chunk) = body.next().await {
+        file.write_all(&chunk?).await?;
+    }
+    file.flush().await?;
+
+    Ok(())
 }
 
-pub async fn get_package_tar(
-    tarball: &UrlString,
+/// Where the validator of the partial download at `path` is kept.
+fn validator_path(path: &Path) -> PathBuf {
+    path.with_extension("validator")
+}
+
+/// The strong `ETag` of a response, or else its `Last-Modified`, as accepted by `If-Range`.
+fn response_validator(response: &reqwest::Response) -> Option<String> {
+    let header = |name| response.headers().get(name)?.to_str().ok();
+
+    header(reqwest::header::ETAG)
+        .filter(|etag| !etag.starts_with("W/"))
+        .or_else(|| header(reqwest::header::LAST_MODIFIED))
+        .map(str::to_string)
+}
+
+/// The first byte of a `206 Partial Content` response, from `Content-Range: bytes 5-9/10`.
+fn content_range_start(response: &reqwest::Response) -> Option<u64> {
+    let content_range = response
+        .headers()
+        .get(reqwest::header::CONTENT_RANGE)?
+        .to_str()
+        .ok()?;
+    let (start, _) = content_range.strip_prefix("bytes ")?.split_once('-')?;
+
+    start.parse().ok()
+}
+
+/// Send the request built by `request`, retrying connection errors, timeouts and 5xx
+/// responses with exponential backoff, and 429 responses after their `Retry-After`,
+/// which is capped at `max_timeout` so a server can't stall the install.
+/// Responses that are neither successful nor `304 Not Modified` after the last retry become an error.
+async fn send_with_retry(
+    url: &reqwest::Url,
     config: &Config,
-) -> Result<reqwest::Response, reqwest::Error> {
-    Ok(config.client.get(tarball.as_str()).send().await?)
+    request: impl Fn() -> reqwest::RequestBuilder,
+) -> Result<reqwest::Response, Error> {
+    let policy = &config.retry_policy;
+    let mut attempt = 0;
+
+    loop {
+        let result = request().timeout(policy.timeout).send().await;
+
+        let delay = match &result {
+            Ok(response) if response.status() == StatusCode::TOO_MANY_REQUESTS => {
+                Some(match retry_after(response) {
+                    Some(delay) => delay.min(policy.max_timeout),
+                    None => policy.backoff(attempt),
+                })
+            }
+            Ok(response) if response.status().is_server_error() => Some(policy.backoff(attempt)),
+            Err(error) if error.is_connect() || error.is_timeout() || error.is_request() => {
+                Some(policy.backoff(attempt))
+            }
+            _ => None,
+        };
+
+        match (delay, result) {
+            (Some(delay), _) if attempt < policy.retries => {
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            (_, Ok(response))
+                if response.status().is_success()
+                    || response.status() == StatusCode::NOT_MODIFIED =>
+            {
+                return Ok(response)
+            }
+            (_, Ok(response)) => return Err(Error::Status(url.to_string(), response.status())),
+            (_, Err(error)) => return Err(Error::Request(url.to_string(), error)),
+        }
+    }
+}
+
+/// The delay requested by a `Retry-After` header, either in seconds or as an HTTP date.
+fn retry_after(response: &reqwest::Response) -> Option<Duration> {
+    let value = response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?;
+
+    match value.parse() {
+        Ok(seconds) => Some(Duration::from_secs(seconds)),
+        Err(_) => httpdate::parse_http_date(value)
+            .ok()?
+            .duration_since(SystemTime::now())
+            .ok(),
+    }
+}
+
+/// Attach the credentials configured for the requested URL, if any.
+/// reqwest drops the header when a redirect leaves the host.
+fn authorize(
+    request: reqwest::RequestBuilder,
+    authorization: &Option<String>,
+) -> reqwest::RequestBuilder {
+    match authorization {
+        Some(authorization) => request.header(reqwest::header::AUTHORIZATION, authorization),
+        None => request,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Instant,
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use crate::metadata_cache::MetadataCache;
+
+    use super::*;
+
+    const PACKUMENT: &str = r#"{ "name": "a", "dist-tags": {}, "versions": {} }"#;
+
+    fn response(status: &str, headers: &str, body: &str) -> Option<String> {
+        Some(format!(
+            "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n{headers}\r\n{body}",
+            body.len()
+        ))
+    }
+
+    /// A local registry answering each connection with the next response,
+    /// or never answering for `None`. Returns a config using it and the requests it received.
+    async fn stub_registry(responses: Vec<Option<String>>) -> (Config, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(vec![]));
+        let counter = requests.clone();
+
+        tokio::spawn(async move {
+            for response in responses {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = vec![0; 4096];
+                let length = socket.read(&mut request).await.unwrap_or_default();
+                counter
+                    .lock()
+                    .unwrap()
+                    .push(String::from_utf8_lossy(&request[..length]).to_lowercase());
+
+                match response {
+                    Some(response) => socket.write_all(response.as_bytes()).await.unwrap(),
+                    None => {
+                        tokio::spawn(async move {
+                            tokio::time::sleep(Duration::from_secs(10)).await;
+                            drop(socket);
+                        });
+                    }
+                }
+            }
+        });
+
+        let config = Config {
+            client: reqwest::Client::builder().no_proxy().build().unwrap(),
+            npmrc: Npmrc::parse(&format!("registry=http://{address}/")),
+            retry_policy: RetryPolicy {
+                retries: 2,
+                factor: 2,
+                min_timeout: Duration::from_millis(10),
+                max_timeout: Duration::from_millis(50),
+                timeout: Duration::from_millis(500),
+            },
+            ..Config::default()
+        };
+
+        (config, requests)
+    }
+
+    #[tokio::test]
+    async fn retries_server_errors() {
+        let (config, requests) = stub_registry(vec![
+            response("503 Service Unavailable", "", ""),
+            response("502 Bad Gateway", "", ""),
+            response("200 OK", "", PACKUMENT),
+        ])
+        .await;
+
+        let package = get_npm_package(&String::from("a"), &config).await.unwrap();
+        assert_eq!(package.name, "a");
+        assert_eq!(requests.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_retries() {
+        let (config, requests) = stub_registry(vec![
+            response("503 Service Unavailable", "", ""),
+            response("503 Service Unavailable", "", ""),
+            response("503 Service Unavailable", "", ""),
+        ])
+        .await;
+
+        let error = get_npm_package(&String::from("a"), &config)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<Error>(),
+            Some(Error::Status(_, StatusCode::SERVICE_UNAVAILABLE))
+        ));
+        assert_eq!(requests.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_client_errors() {
+        let (config, requests) = stub_registry(vec![response("404 Not Found", "", "")]).await;
+
+        assert!(get_npm_package(&String::from("a"), &config).await.is_err());
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn honors_retry_after() {
+        let (mut config, requests) = stub_registry(vec![
+            response("429 Too Many Requests", "Retry-After: 1\r\n", ""),
+            response("200 OK", "", PACKUMENT),
+        ])
+        .await;
+        config.retry_policy.max_timeout = Duration::from_secs(2);
+
+        let start = Instant::now();
+        get_npm_package(&String::from("a"), &config).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_secs(1));
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn caps_retry_after() {
+        let (config, requests) = stub_registry(vec![
+            response("429 Too Many Requests", "Retry-After: 86400\r\n", ""),
+            response("200 OK", "", PACKUMENT),
+        ])
+        .await;
+
+        let start = Instant::now();
+        get_npm_package(&String::from("a"), &config).await.unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn retries_timeouts() {
+        let (config, requests) = stub_registry(vec![None, response("200 OK", "", PACKUMENT)]).await;
+
+        get_npm_package(&String::from("a"), &config).await.unwrap();
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn revalidates_cached_packuments() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let (config, requests) = stub_registry(vec![
+            response(
+                "200 OK",
+                "ETag: \"v1\"\r\nLast-Modified: Wed, 21 Oct 2015 07:28:00 GMT\r\n",
+                PACKUMENT,
+            ),
+            response("304 Not Modified", "", ""),
+        ])
+        .await;
+        let config = Config {
+            metadata_cache: MetadataCache {
+                dir: Some(cache_dir.path().to_path_buf()),
+                max_age: chrono::Duration::zero(),
+            },
+            ..config
+        };
+
+        let name = String::from("a");
+        get_npm_package(&name, &config).await.unwrap();
+        let package = get_npm_package(&name, &config).await.unwrap();
+        assert_eq!(package.name, "a");
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert!(!requests[0].contains("if-none-match"));
+        assert!(requests[1].contains("if-none-match: \"v1\""));
+        assert!(requests[1].contains("if-modified-since: wed, 21 oct 2015 07:28:00 gmt"));
+    }
+
+    #[tokio::test]
+    async fn serves_fresh_packuments_from_cache() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let (config, requests) = stub_registry(vec![response("200 OK", "", PACKUMENT)]).await;
+        let config = Config {
+            metadata_cache: MetadataCache {
+                dir: Some(cache_dir.path().to_path_buf()),
+                max_age: chrono::Duration::minutes(5),
+            },
+            ..config
+        };
+
+        let name = String::from("a");
+        get_npm_package(&name, &config).await.unwrap();
+        get_npm_package(&name, &config).await.unwrap();
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn only_caches_packuments_that_parse() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let (config, requests) = stub_registry(vec![
+            response("200 OK", "", "<html>"),
+            response("200 OK", "", PACKUMENT),
+            response("304 Not Modified", "", ""),
+        ])
+        .await;
+        let config = Config {
+            metadata_cache: MetadataCache {
+                dir: Some(cache_dir.path().to_path_buf()),
+                max_age: chrono::Duration::minutes(5),
+            },
+            ..config
+        };
+
+        let name = String::from("a");
+        assert!(get_npm_package(&name, &config).await.is_err());
+        get_npm_package(&name, &config).await.unwrap();
+        assert_eq!(requests.lock().unwrap().len(), 2);
+
+        let uncached = Config {
+            metadata_cache: MetadataCache::default(),
+            ..config
+        };
+        let error = get_npm_package(&name, &uncached).await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(Error::Status(_, StatusCode::NOT_MODIFIED))
+        ));
+    }
+
+    #[tokio::test]
+    async fn offline_modes_use_stale_cache() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let (config, requests) = stub_registry(vec![response("200 OK", "", PACKUMENT)]).await;
+        let config = Config {
+            metadata_cache: MetadataCache {
+                dir: Some(cache_dir.path().to_path_buf()),
+                max_age: chrono::Duration::zero(),
+            },
+            ..config
+        };
+
+        let error = get_npm_package(
+            &String::from("a"),
+            &Config {
+                network_mode: NetworkMode::Offline,
+                ..Config::default()
+            },
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(Error::Offline(_))));
+
+        let name = String::from("a");
+        get_npm_package(&name, &config).await.unwrap();
+
+        let mut config = config;
+        for network_mode in [NetworkMode::PreferOffline, NetworkMode::Offline] {
+            config.network_mode = network_mode;
+            get_npm_package(&name, &config).await.unwrap();
+        }
+        assert_eq!(requests.lock().unwrap().len(), 1);
+
+        let error = get_package_tar(
+            &UrlString::new(String::from("https://registry.npmjs.org/a/-/a-1.0.0.tgz")),
+            0,
+            None,
+            &config,
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(Error::Offline(_))));
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_next_registry() {
+        let (mut config, primary_requests) =
+            stub_registry(vec![response("404 Not Found", "", "")]).await;
+        let (fallback, fallback_requests) = stub_registry(vec![response(
+            "200 OK",
+            "",
+            r#"{ "name": "a", "dist-tags": {}, "versions": { "1.0.0": {
+                "name": "a", "version": "1.0.0",
+                "dist": { "shasum": "", "tarball": "https://registry.npmjs.org/a/-/a-1.0.0.tgz" }
+            } } }"#,
+        )])
+        .await;
+        let primary = config.npmrc.registry("a");
+        let fallback = fallback.npmrc.registry("a");
+        config.npmrc = Npmrc::parse(&format!(
+            "registry={primary}\nregistry-fallbacks={fallback}"
+        ));
+
+        let package = get_npm_package(&String::from("a"), &config).await.unwrap();
+
+        assert_eq!(
+            *package.versions[0].dist.tarball,
+            format!("{fallback}a/-/a-1.0.0.tgz")
+        );
+        assert_eq!(primary_requests.lock().unwrap().len(), 1);
+        assert_eq!(fallback_requests.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn goes_straight_to_preferred_registry() {
+        let (mut config, primary_requests) =
+            stub_registry(vec![response("200 OK", "", PACKUMENT)]).await;
+        let (fallback, fallback_requests) =
+            stub_registry(vec![response("200 OK", "", PACKUMENT)]).await;
+        let primary = config.npmrc.registry("a");
+        let fallback = fallback.npmrc.registry("a");
+        config.npmrc = Npmrc::parse(&format!(
+            "registry={primary}\nregistry-fallbacks={fallback}"
+        ));
+
+        get_npm_package_preferring(&String::from("a"), &fallback, &config)
+            .await
+            .unwrap();
+
+        assert!(primary_requests.lock().unwrap().is_empty());
+        assert_eq!(fallback_requests.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn falls_back_after_server_errors() {
+        let (mut config, primary_requests) = stub_registry(vec![
+            response("503 Service Unavailable", "", ""),
+            response("503 Service Unavailable", "", ""),
+            response("503 Service Unavailable", "", ""),
+        ])
+        .await;
+        let (fallback, fallback_requests) =
+            stub_registry(vec![response("200 OK", "", PACKUMENT)]).await;
+        let primary = config.npmrc.registry("a");
+        let fallback = fallback.npmrc.registry("a");
+        config.npmrc = Npmrc::parse(&format!(
+            "registry={primary}\nregistry-fallbacks={fallback}"
+        ));
+
+        let package = get_npm_package(&String::from("a"), &config).await.unwrap();
+
+        assert_eq!(package.name, "a");
+        assert_eq!(primary_requests.lock().unwrap().len(), 3);
+        assert_eq!(fallback_requests.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn resumes_interrupted_tarball_downloads() {
+        let (config, requests) = stub_registry(vec![
+            Some(String::from(
+                "HTTP/1.1 200 OK\r\nContent-Length: 10\r\nETag: \"v1\"\r\nConnection: close\r\n\r\nhello",
+            )),
+            response(
+                "206 Partial Content",
+                "Content-Range: bytes 5-9/10\r\n",
+                "world",
+            ),
+        ])
+        .await;
+        let tarball = UrlString::new(format!("{}a/-/a-1.0.0.tgz", config.npmrc.registry("a")));
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a-1.0.0.tgz.partial");
+
+        download_tarball(&tarball, &path, &config).await.unwrap();
+
+        assert_eq!(std::fs::read(&path).unwrap(), b"helloworld");
+        assert!(!validator_path(&path).exists());
+        let requests = requests.lock().unwrap();
+        assert!(!requests[0].contains("range:"));
+        assert!(requests[1].contains("range: bytes=5-"));
+        assert!(requests[1].contains("if-range: \"v1\""));
+    }
+
+    #[tokio::test]
+    async fn restarts_downloads_that_cannot_be_resumed() {
+        let (config, requests) = stub_registry(vec![
+            response(
+                "206 Partial Content",
+                "Content-Range: bytes 0-9/10\r\n",
+                "helloworld",
+            ),
+            response("200 OK", "", "helloworld"),
+            response("200 OK", "", "helloworld"),
+        ])
+        .await;
+        let tarball = UrlString::new(format!("{}a/-/a-1.0.0.tgz", config.npmrc.registry("a")));
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a-1.0.0.tgz.partial");
+
+        // The range the server answered with doesn't start where the download stopped.
+        std::fs::write(&path, b"hello").unwrap();
+        std::fs::write(validator_path(&path), "\"v1\"").unwrap();
+        download_tarball(&tarball, &path, &config).await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"helloworld");
+
+        // Without a validator, the partial download may belong to another tarball.
+        std::fs::write(&path, b"hello").unwrap();
+        download_tarball(&tarball, &path, &config).await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"helloworld");
+
+        let requests = requests.lock().unwrap();
+        assert!(requests[0].contains("range: bytes=5-"));
+        assert!(!requests[1].contains("range:"));
+        assert!(!requests[2].contains("range:"));
+    }
+
+    #[tokio::test]
+    async fn waits_for_metadata_permit() {
+        let (config, requests) = stub_registry(vec![response("200 OK", "", PACKUMENT)]).await;
+        let config = Config {
+            network_concurrency: NetworkConcurrency::new(2),
+            ..config
+        };
+
+        let held = config.network_concurrency.metadata.acquire().await.unwrap();
+        // Tarball permits don't count against packument requests.
+        let _tarball = config.network_concurrency.tarballs.acquire().await.unwrap();
+        let name = String::from("a");
+        let mut fetch = Box::pin(get_npm_package(&name, &config));
+
+        assert!(tokio::time::timeout(Duration::from_millis(100), &mut fetch)
+            .await
+            .is_err());
+        assert_eq!(requests.lock().unwrap().len(), 0);
+
+        drop(held);
+        fetch.await.unwrap();
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn splits_network_concurrency() {
+        let permits = |limit| {
+            let concurrency = NetworkConcurrency::new(limit);
+            (
+                concurrency.metadata.available_permits(),
+                concurrency.tarballs.available_permits(),
+            )
+        };
+
+        assert_eq!(permits(16), (8, 8));
+        assert_eq!(permits(5), (3, 2));
+        assert_eq!(permits(1), (1, 1));
+        assert_eq!(permits(0), (1, 1));
+    }
+
+    #[test]
+    fn backoff_grows_up_to_max_timeout() {
+        let policy = RetryPolicy {
+            retries: 5,
+            factor: 10,
+            min_timeout: Duration::from_millis(100),
+            max_timeout: Duration::from_secs(5),
+            timeout: Duration::from_secs(1),
+        };
+
+        let first = policy.backoff(0);
+        assert!(first >= Duration::from_millis(50) && first <= Duration::from_millis(100));
+        let second = policy.backoff(1);
+        assert!(second >= Duration::from_millis(500) && second <= Duration::from_secs(1));
+        assert!(policy.backoff(4) <= Duration::from_secs(5));
+    }
+
+    #[test]
+    fn reads_retry_policy_from_npmrc() {
+        let npmrc =
+            Npmrc::parse("fetch-retries=4\nfetch-retry-mintimeout=1000\nfetch-timeout=30000");
+        let policy = RetryPolicy::from_npmrc(&npmrc).unwrap();
+
+        assert_eq!(policy.retries, 4);
+        assert_eq!(policy.min_timeout, Duration::from_secs(1));
+        assert_eq!(policy.max_timeout, Duration::from_secs(60));
+        assert_eq!(policy.timeout, Duration::from_secs(30));
+    }
 }
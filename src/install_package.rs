@@ -1,26 +1,131 @@
 use futures::future::join_all;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 use crate::{
     config::Config,
-    dependency_resolver::resolve_deps,
+    dependency_graph::DependencyGraph,
+    dependency_resolver::{resolve_deps, LockedVersions},
     downloader::download_packages,
-    linker::{hardlink_package, symlink_dep, symlink_direct},
-    npm::VersionRangeSpecifier,
-    package_manifest::update_package_manifest,
+    jsr::JSR_PROTOCOL,
+    linker::{
+        hardlink_package, remove_installed_package, symlink_dep, symlink_direct, unlink_direct,
+    },
+    lockfile::{get_package_key, read_locked_versions, read_lockfile, write_lockfile, Lockfile},
+    npm::{Version, VersionRangeSpecifier},
+    package_manifest::{
+        get_manifest_dependencies, remove_from_package_manifest, update_package_manifest,
+    },
 };
 
+/// Add packages to the project.
+/// Packages already in the lockfile stay pinned, only the added ones are resolved.
 pub async fn install_package(
     deps: HashMap<String, VersionRangeSpecifier>,
     config: &Config,
 ) -> anyhow::Result<()> {
-    let resolved_deps = resolve_deps(deps, config).await?;
+    let mut all_deps = get_manifest_dependencies().await?;
+    all_deps.extend(deps.clone());
 
-    let top_level = download_packages(&resolved_deps, config).await?;
+    let locked = read_locked_versions(config).await?;
+    let graph = install(all_deps, &locked, config).await?;
+
+    for top_level_dep in graph.roots() {
+        let top_level_dep = &graph.node(top_level_dep).version;
+        let Some(requested) = deps.get(&top_level_dep.name) else {
+            continue;
+        };
+        let range = get_manifest_range(requested, &top_level_dep.version);
+
+        update_package_manifest(HashMap::from([(top_level_dep.name.to_owned(), range)])).await?;
+    }
+
+    Ok(())
+}
+
+/// The range to save in the manifest for a package added with `requested`.
+/// Ranges and catalogs are kept as requested, while bare names and tags such as `latest`
+/// are saved as `^version`.
+fn get_manifest_range(
+    requested: &VersionRangeSpecifier,
+    version: &Version,
+) -> VersionRangeSpecifier {
+    let (protocol, range) = match requested.strip_prefix(JSR_PROTOCOL) {
+        Some(range) => (JSR_PROTOCOL, range),
+        None => ("", requested.as_str()),
+    };
+
+    if range.starts_with("catalog:") || range.parse::<node_semver::Range>().is_ok() {
+        requested.to_owned()
+    } else {
+        VersionRangeSpecifier::new(format!("{protocol}^{version}"))
+    }
+}
+
+/// Remove direct dependencies from the project, keeping every other package pinned.
+/// Packages only installed for the removed ones are deleted from the store.
+pub async fn remove_package(packages: Vec<String>, config: &Config) -> anyhow::Result<()> {
+    let mut deps = get_manifest_dependencies().await?;
+    for package in &packages {
+        deps.remove(package);
+        unlink_direct(package).await?;
+    }
+
+    let previous = read_lockfile().await?;
+    let locked = read_locked_versions(config).await?;
+    let graph = install(deps, &locked, config).await?;
+    prune(previous, &graph).await?;
+
+    remove_from_package_manifest(packages).await?;
+
+    Ok(())
+}
+
+/// Re-resolve the given packages, keeping every other package pinned.
+/// The versions they replace are deleted from the store.
+pub async fn upgrade_package(packages: Vec<String>, config: &Config) -> anyhow::Result<()> {
+    let deps = get_manifest_dependencies().await?;
+
+    let previous = read_lockfile().await?;
+    let mut locked = read_locked_versions(config).await?;
+    for package in &packages {
+        locked.unlock(package);
+    }
+
+    let graph = install(deps, &locked, config).await?;
+    prune(previous, &graph).await?;
+
+    Ok(())
+}
+
+/// Delete the packages of the `previous` lockfile that are no longer in `graph`.
+async fn prune(previous: Option<Lockfile>, graph: &DependencyGraph) -> anyhow::Result<()> {
+    for package in previous
+        .into_iter()
+        .flat_map(|lockfile| lockfile.packages.into_values())
+    {
+        if graph.find(&package.name, &package.version).is_none() {
+            remove_installed_package(&package.name, &package.version).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Resolve, download and link `deps`, reusing `locked` versions where possible.
+pub async fn install(
+    deps: HashMap<String, VersionRangeSpecifier>,
+    locked: &LockedVersions,
+    config: &Config,
+) -> anyhow::Result<DependencyGraph> {
+    let graph = resolve_deps(deps, locked, config).await?;
+
+    write_lockfile(Lockfile::new(&graph, config)).await?;
+
+    download_packages(&graph, config).await?;
 
     let mut futures = vec![];
-    for dep in resolved_deps.iter() {
-        futures.push(hardlink_package(&dep.version.name, &dep.version.version))
+    for (_, node) in graph.nodes() {
+        futures.push(hardlink_package(&node.version.name, &node.version.version))
     }
     let _results = join_all(futures).await;
     println!("hardlink: ");
@@ -33,8 +138,9 @@ pub async fn install_package(
     );
 
     let mut futures = vec![];
-    for package in resolved_deps.iter() {
+    for (_, package) in graph.nodes() {
         for dep in package.dependencies.iter() {
+            let dep = &graph.node(*dep).version;
             futures.push(symlink_dep(
                 &dep.name,
                 &dep.version,
@@ -53,15 +159,83 @@ pub async fn install_package(
             .collect::<Vec<_>>()
     );
 
-    for top_level_dep in top_level {
-        symlink_direct(&top_level_dep.version.name, &top_level_dep.version.version).await?;
+    for top_level_dep in graph.roots() {
+        let top_level_dep = &graph.node(top_level_dep).version;
+        symlink_direct(&top_level_dep.name, &top_level_dep.version).await?;
+    }
+
+    print_deprecations(&graph);
+    print_cycles(&graph);
+
+    Ok(graph)
+}
 
-        update_package_manifest(HashMap::from([(
-            top_level_dep.version.name,
-            VersionRangeSpecifier::new(format!("^{}", top_level_dep.version.version)),
-        )]))
-        .await?;
+/// Print the deprecation messages of the installed versions,
+/// grouped by whether they are direct or transitive dependencies.
+fn print_deprecations(graph: &DependencyGraph) {
+    let mut direct = BTreeMap::new();
+    let mut transitive = BTreeMap::new();
+
+    for (id, node) in graph.nodes() {
+        if let Some(message) = &node.version.deprecated {
+            let key = get_package_key(&node.version.name, &node.version.version);
+            if node.is_root {
+                direct.insert(key, message.to_owned());
+            } else {
+                let message = match graph.shortest_path_to_root(id) {
+                    Some(path) => {
+                        let path: Vec<_> = path.iter().rev().copied().collect();
+                        format!("{message} (via {})", graph.format_path(&path))
+                    }
+                    None => message.to_owned(),
+                };
+                transitive.insert(key, message);
+            }
+        }
     }
 
-    Ok(())
+    for (title, deprecations) in [("direct", direct), ("transitive", transitive)] {
+        if deprecations.is_empty() {
+            continue;
+        }
+
+        println!("deprecated {title} dependencies:");
+        for (package, message) in deprecations {
+            println!("  {package}: {message}");
+        }
+    }
+}
+
+fn print_cycles(graph: &DependencyGraph) {
+    let cycles = graph.cycles();
+    if cycles.is_empty() {
+        return;
+    }
+
+    println!("dependency cycles:");
+    for cycle in cycles {
+        println!("  {}", graph.format_path(&cycle));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_requested_ranges_in_manifest() {
+        let version = Version::new(String::from("4.17.21"));
+        let range = |range: &str| {
+            get_manifest_range(&VersionRangeSpecifier::new(range.to_string()), &version)
+                .as_str()
+                .to_string()
+        };
+
+        assert_eq!(range("~4.17.0"), "~4.17.0");
+        assert_eq!(range("4.17.21"), "4.17.21");
+        assert_eq!(range("catalog:"), "catalog:");
+        assert_eq!(range("latest"), "^4.17.21");
+        assert_eq!(range("jsr:~4.17.0"), "jsr:~4.17.0");
+        assert_eq!(range("jsr:latest"), "jsr:^4.17.21");
+    }
 }
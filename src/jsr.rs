@@ -0,0 +1,84 @@
+use thiserror::Error;
+
+use crate::npm::{parse_package_spec, VersionRangeSpecifier};
+
+pub const JSR_PROTOCOL: &str = "jsr:";
+/// The scope JSR packages are published under in its npm compatibility registry.
+pub const JSR_NPM_SCOPE: &str = "@jsr/";
+pub const DEFAULT_JSR_REGISTRY_URL: &str = "https://npm.jsr.io/";
+
+#[derive(Error, Debug, PartialEq)]
+pub enum JsrError {
+    #[error("JSR package {0} must be scoped, as in @scope/name")]
+    UnscopedPackage(String),
+}
+
+/// Map a `jsr:` spec of `package_name` to the name and range of the package in JSR's npm
+/// compatibility registry, so `@std/path` with `jsr:^1.0.0` becomes `@jsr/std__path` with `^1.0.0`.
+/// The spec may also name the package itself, as in `jsr:@std/path@^1.0.0`.
+/// Returns `None` for any other spec.
+pub fn parse_jsr_spec(
+    package_name: &str,
+    version_range: &VersionRangeSpecifier,
+) -> Result<Option<(String, VersionRangeSpecifier)>, JsrError> {
+    let Some(spec) = version_range.strip_prefix(JSR_PROTOCOL) else {
+        return Ok(None);
+    };
+
+    let (jsr_name, range) = if spec.starts_with('@') {
+        parse_package_spec(spec)
+    } else if spec.is_empty() {
+        (
+            package_name.to_string(),
+            VersionRangeSpecifier::new(String::from("latest")),
+        )
+    } else {
+        (
+            package_name.to_string(),
+            VersionRangeSpecifier::new(spec.to_string()),
+        )
+    };
+
+    let npm_name = jsr_name
+        .strip_prefix('@')
+        .and_then(|name| name.split_once('/'))
+        .map(|(scope, name)| format!("{JSR_NPM_SCOPE}{scope}__{name}"))
+        .ok_or(JsrError::UnscopedPackage(jsr_name.clone()))?;
+
+    Ok(Some((npm_name, range)))
+}
+
+/// Whether `package_name` must be fetched from the JSR registry rather than npm.
+pub fn is_jsr_package(package_name: &str) -> bool {
+    package_name.starts_with(JSR_NPM_SCOPE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(range: &str) -> VersionRangeSpecifier {
+        VersionRangeSpecifier::new(range.to_string())
+    }
+
+    #[test]
+    fn maps_jsr_specs_to_npm_scope() {
+        assert_eq!(
+            parse_jsr_spec("@std/path", &range("jsr:^1.0.0")),
+            Ok(Some((String::from("@jsr/std__path"), range("^1.0.0"))))
+        );
+        assert_eq!(
+            parse_jsr_spec("path", &range("jsr:@std/path@^1.0.0")),
+            Ok(Some((String::from("@jsr/std__path"), range("^1.0.0"))))
+        );
+        assert_eq!(
+            parse_jsr_spec("@std/path", &range("jsr:")),
+            Ok(Some((String::from("@jsr/std__path"), range("latest"))))
+        );
+        assert_eq!(parse_jsr_spec("@std/path", &range("^1.0.0")), Ok(None));
+        assert_eq!(
+            parse_jsr_spec("path", &range("jsr:^1.0.0")),
+            Err(JsrError::UnscopedPackage(String::from("path")))
+        );
+    }
+}
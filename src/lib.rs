@@ -1,13 +1,24 @@
+pub mod cacache;
+pub mod catalogs;
 pub mod config;
+pub mod dependency_graph;
 pub mod dependency_resolver;
 pub mod downloader;
 pub mod http;
 pub mod install_manifest;
 pub mod install_package;
+pub mod jsr;
 mod linker;
+pub mod lockfile;
+pub mod metadata_cache;
 pub mod npm;
+pub mod npmrc;
+pub mod package_extensions;
 mod package_manifest;
-mod resolve_version_range;
+pub mod packument_cache;
+pub mod registry;
+pub mod resolve_version_range;
+pub mod shrinkwrap;
 
 pub const STORE_FOLDER: &str = ".fpm";
 pub const DEPS_FOLDER: &str = "node_modules";
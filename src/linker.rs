@@ -12,7 +12,7 @@ pub async fn symlink_dep(
     dest_name: &String,
     dest_version: &Version,
 ) -> anyhow::Result<()> {
-    let original = get_dep_symlink_path(&dep_name, dep_version);
+    let original = get_dep_symlink_path(dep_name, dep_version);
 
     let link = get_local_store_package_path(dest_name, dest_version);
     let mut parent = link
@@ -26,27 +26,39 @@ pub async fn symlink_dep(
             .to_path_buf();
     }
 
-    parent = parent.join(&dep_name);
+    parent = parent.join(dep_name);
 
     let name = dep_name.clone();
 
-    task::spawn_blocking(
-        move || match std::os::unix::fs::symlink(&original, &parent) {
-            Err(error) if error.kind() == ErrorKind::AlreadyExists => Ok(()),
-            Err(error) => {
-                // TODO: check what pnpm does with @floating-ui/react-dom
-                // I bet they convert the / to something else like _
+    task::spawn_blocking(move || match replace_symlink(&original, &parent) {
+        Err(error) => {
+            // TODO: check what pnpm does with @floating-ui/react-dom
+            // I bet they convert the / to something else like _
 
-                println!("{:?}, {:?}, {:?}, {:?}", error, original, parent, name);
+            println!("{:?}, {:?}, {:?}, {:?}", error, original, parent, name);
 
-                return Err(error.into());
-            }
-            Ok(_) => Ok(()),
-        },
-    )
+            Err(error.into())
+        }
+        Ok(_) => Ok(()),
+    })
     .await?
 }
 
+/// Link `link` to `original`, replacing the link left by a previous install,
+/// which may point at another version.
+fn replace_symlink(original: &Path, link: &Path) -> Result<()> {
+    match std::fs::remove_file(link) {
+        Err(error) if error.kind() != ErrorKind::NotFound => return Err(error),
+        _ => {}
+    }
+
+    match std::os::unix::fs::symlink(original, link) {
+        // Linked by another instance of the same package in this install.
+        Err(error) if error.kind() == ErrorKind::AlreadyExists => Ok(()),
+        result => result,
+    }
+}
+
 pub async fn symlink_direct(name: &String, version: &Version) -> Result<()> {
     let path_base = if name.starts_with("@") {
         Path::new("../")
@@ -66,9 +78,41 @@ pub async fn symlink_direct(name: &String, version: &Version) -> Result<()> {
         tokio::fs::create_dir_all(parent).await?;
     }
 
+    // The link may point at a previously installed version.
+    unlink_direct(name).await?;
+
     task::spawn_blocking(|| std::os::unix::fs::symlink(original, link)).await?
 }
 
+/// Remove the link to a direct dependency, if it exists.
+pub async fn unlink_direct(name: &String) -> Result<()> {
+    match tokio::fs::remove_file(Path::new(DEPS_FOLDER).join(name)).await {
+        Err(error) if error.kind() != ErrorKind::NotFound => Err(error),
+        _ => Ok(()),
+    }
+}
+
+/// Remove an installed package from the store and from the packages linked into the project.
+pub async fn remove_installed_package(name: &String, version: &Version) -> Result<()> {
+    let folder_name = if name.starts_with("@") {
+        name.replace("/", "+")
+    } else {
+        name.to_string()
+    };
+    let linked = Path::new(DEPS_FOLDER)
+        .join(STORE_FOLDER)
+        .join(format!("{}@{}", folder_name, version));
+
+    for path in [get_store_package_path(name, version), linked] {
+        match tokio::fs::remove_dir_all(path).await {
+            Err(error) if error.kind() != ErrorKind::NotFound => return Err(error),
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
 fn get_dep_symlink_path(name: &String, version: &Version) -> PathBuf {
     let folder_name = if name.starts_with("@") {
         name.replace("/", "+")
@@ -94,7 +138,7 @@ pub fn get_local_store_package_path(package_name: &String, version: &Version) ->
         .join(STORE_FOLDER)
         .join(format!("{}@{}", folder_name, &version))
         .join("node_modules")
-        .join(&package_name)
+        .join(package_name)
 }
 
 /// Hardlink all files form `source` recursively into `dest`.
@@ -115,39 +159,37 @@ pub async fn hardlink_package(package_name: &String, version: &Version) -> anyho
 fn hardlink(source: PathBuf, dest: PathBuf) -> anyhow::Result<()> {
     let files = std::fs::read_dir(source)?;
 
-    for file in files {
-        if let Ok(file) = file {
-            if let Ok(file_type) = file.file_type() {
-                if file_type.is_dir() && file.file_name() != "node_modules" {
-                    let sub_dir = dest.join(&file.file_name());
-
-                    match std::fs::create_dir_all(&sub_dir) {
-                        Err(error) if error.kind() != ErrorKind::AlreadyExists => {
-                            return Err(error.into())
-                        }
-                        _ => {}
+    for file in files.flatten() {
+        if let Ok(file_type) = file.file_type() {
+            if file_type.is_dir() && file.file_name() != "node_modules" {
+                let sub_dir = dest.join(file.file_name());
+
+                match std::fs::create_dir_all(&sub_dir) {
+                    Err(error) if error.kind() != ErrorKind::AlreadyExists => {
+                        return Err(error.into())
                     }
+                    _ => {}
+                }
 
-                    match hardlink(file.path().clone(), sub_dir) {
-                        Err(error) if error.downcast_ref() == Some(&ErrorKind::AlreadyExists) => {
-                            return Err(error)
-                        }
-                        _ => {}
+                match hardlink(file.path().clone(), sub_dir) {
+                    Err(error) if error.downcast_ref() == Some(&ErrorKind::AlreadyExists) => {
+                        return Err(error)
                     }
-                } else if file_type.is_file() {
-                    match std::fs::create_dir_all(&dest) {
-                        Err(error) if error.kind() != ErrorKind::AlreadyExists => {
-                            return Err(error.into())
-                        }
-                        _ => {}
+                    _ => {}
+                }
+            } else if file_type.is_file() {
+                match std::fs::create_dir_all(&dest) {
+                    Err(error) if error.kind() != ErrorKind::AlreadyExists => {
+                        return Err(error.into())
                     }
+                    _ => {}
+                }
 
-                    match std::fs::hard_link(file.path(), dest.join(file.file_name())) {
-                        Err(error) if error.kind() != ErrorKind::AlreadyExists => {
-                            return Err(error.into())
-                        }
-                        _ => {}
+                match std::fs::hard_link(file.path(), dest.join(file.file_name())) {
+                    Err(error) if error.kind() != ErrorKind::AlreadyExists => {
+                        return Err(error.into())
                     }
+                    _ => {}
                 }
             }
         }
@@ -184,6 +226,19 @@ mod tests {
         )
     }
 
+    #[test]
+    fn replaces_existing_symlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        let link = dir.path().join("b");
+        std::fs::create_dir(dir.path().join("b@1.0.0")).unwrap();
+        std::fs::create_dir(dir.path().join("b@2.0.0")).unwrap();
+
+        replace_symlink(Path::new("b@1.0.0"), &link).unwrap();
+        replace_symlink(Path::new("b@2.0.0"), &link).unwrap();
+
+        assert_eq!(std::fs::read_link(&link).unwrap(), Path::new("b@2.0.0"));
+    }
+
     #[test]
     fn local_store_path_no_scope() {
         let path = get_local_store_package_path(
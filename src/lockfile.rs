@@ -0,0 +1,389 @@
+use std::{collections::BTreeMap, fs, io::ErrorKind};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::task;
+
+use crate::{
+    config::{Config, ResolutionMode},
+    dependency_graph::DependencyGraph,
+    dependency_resolver::LockedVersions,
+    npm::{Engines, NpmPackageVersion, NpmVersionDist, UrlString, Version, VersionRangeSpecifier},
+    package_extensions::package_extensions_checksum,
+};
+
+pub const LOCKFILE_NAME: &str = "fpm-lock.json";
+const LOCKFILE_VERSION: u32 = 1;
+
+/// The resolved dependency graph, written next to the manifest after every install.
+/// Tarballs hosted on a registry are stored relative to it, so the lockfile works with any mirror.
+#[derive(Deserialize, Serialize, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Lockfile {
+    pub lockfile_version: u32,
+    pub settings: LockfileSettings,
+
+    /// Direct dependencies of the project, mapped to their resolved version.
+    pub dependencies: BTreeMap<String, Version>,
+
+    /// Every resolved package, keyed by `name@version`.
+    pub packages: BTreeMap<String, LockedPackage>,
+}
+
+/// Resolution settings that affect which versions were picked.
+/// Locked versions are only reused while these stay the same.
+#[derive(Deserialize, Serialize, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct LockfileSettings {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub before: Option<DateTime<Utc>>,
+
+    /// In minutes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub minimum_release_age: Option<i64>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub minimum_release_age_exclude: Vec<String>,
+
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub prefer_non_deprecated: bool,
+
+    #[serde(default, skip_serializing_if = "ResolutionMode::is_highest")]
+    pub resolution_mode: ResolutionMode,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub package_extensions_checksum: Option<String>,
+}
+
+impl LockfileSettings {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            before: config.before,
+            minimum_release_age: config.minimum_release_age.map(|age| age.num_minutes()),
+            minimum_release_age_exclude: config.minimum_release_age_exclude.to_owned(),
+            prefer_non_deprecated: config.prefer_non_deprecated,
+            resolution_mode: config.resolution_mode,
+            package_extensions_checksum: package_extensions_checksum(&config.package_extensions),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct LockedPackage {
+    pub name: String,
+    pub version: Version,
+    pub resolution: LockedResolution,
+
+    /// The dependency ranges as published, which are pinned to locked versions when reused.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub dependencies: BTreeMap<String, VersionRangeSpecifier>,
+
+    /// Optional dependencies added by package extensions.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub optional_dependencies: BTreeMap<String, VersionRangeSpecifier>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub engines: Option<Engines>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deprecated: Option<String>,
+
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub has_shrinkwrap: bool,
+
+    /// The packages resolved within the shrinkwrap of this package, keyed like `packages`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub shrinkwrapped: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct LockedResolution {
+    /// Relative to the registry of the package when hosted on one, like `a/-/a-1.0.0.tgz`.
+    pub tarball: UrlString,
+
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub shasum: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub integrity: Option<String>,
+
+    /// The fallback registry that served the package, which the tarball is relative to.
+    /// Packages served by their first registry don't record it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub registry: Option<String>,
+}
+
+impl LockedResolution {
+    fn new(version: &NpmPackageVersion, config: &Config) -> Self {
+        let (tarball, registry) = match config.split_tarball_url(&version.dist.tarball) {
+            Some((registry, path)) => {
+                let fallback = config
+                    .registry_urls(&version.name)
+                    .into_iter()
+                    .skip(1)
+                    .find(|fallback| *fallback == registry);
+                (path.to_string(), fallback)
+            }
+            None => (version.dist.tarball.to_string(), None),
+        };
+
+        Self {
+            tarball: UrlString::new(tarball),
+            shasum: version.dist.shasum.to_owned(),
+            integrity: version.dist.integrity.to_owned(),
+            registry,
+        }
+    }
+
+    /// The absolute tarball URL, resolving relative tarballs against the registry
+    /// that served the package, or else the registry of `package_name`.
+    fn tarball_url(&self, package_name: &str, config: &Config) -> UrlString {
+        if self.tarball.contains("://") {
+            return self.tarball.to_owned();
+        }
+
+        let registry = match &self.registry {
+            Some(registry) => registry.to_owned(),
+            None => config.registry_url(package_name),
+        };
+
+        UrlString::new(format!("{registry}{}", self.tarball))
+    }
+}
+
+impl Lockfile {
+    pub fn new(graph: &DependencyGraph, config: &Config) -> Self {
+        let mut dependencies = BTreeMap::new();
+        let mut packages = BTreeMap::new();
+
+        for (_, node) in graph.nodes() {
+            let version = &node.version;
+
+            if node.is_root {
+                dependencies.insert(version.name.to_owned(), version.version.to_owned());
+            }
+
+            packages.insert(
+                get_package_key(&version.name, &version.version),
+                LockedPackage {
+                    name: version.name.to_owned(),
+                    version: version.version.to_owned(),
+                    resolution: LockedResolution::new(version, config),
+                    dependencies: version.dependencies.to_owned(),
+                    optional_dependencies: version.optional_dependencies.to_owned(),
+                    engines: version.engines.to_owned(),
+                    deprecated: version.deprecated.to_owned(),
+                    has_shrinkwrap: version.has_shrinkwrap,
+                    shrinkwrapped: node
+                        .shrinkwrapped
+                        .iter()
+                        .map(|id| {
+                            let version = &graph.node(*id).version;
+                            get_package_key(&version.name, &version.version)
+                        })
+                        .collect(),
+                },
+            );
+        }
+
+        Self {
+            lockfile_version: LOCKFILE_VERSION,
+            settings: LockfileSettings::new(config),
+            dependencies,
+            packages,
+        }
+    }
+
+    /// The locked packages as they were published.
+    /// Their dependencies are pinned while resolving, as long as a locked version satisfies them.
+    /// Registry-relative tarballs are resolved against the registry that served their package,
+    /// which their packument is fetched from too if they're resolved again.
+    pub fn locked_versions(&self, config: &Config) -> LockedVersions {
+        let versions: BTreeMap<_, _> = self
+            .packages
+            .iter()
+            .map(|(key, package)| (key, Self::locked_version(package, config)))
+            .collect();
+
+        let mut locked = LockedVersions::new(versions.values().cloned());
+        for (key, package) in &self.packages {
+            if !package.shrinkwrapped.is_empty() {
+                let pinned = package
+                    .shrinkwrapped
+                    .iter()
+                    .filter_map(|key| versions.get(key).cloned())
+                    .collect();
+                locked.add_shrinkwrap(&versions[key], pinned);
+            }
+            if let Some(registry) = &package.resolution.registry {
+                locked.set_registry(&package.name, registry.to_owned());
+            }
+        }
+
+        locked
+    }
+
+    fn locked_version(package: &LockedPackage, config: &Config) -> NpmPackageVersion {
+        NpmPackageVersion {
+            name: package.name.to_owned(),
+            version: package.version.to_owned(),
+            dependencies: package.dependencies.to_owned(),
+            optional_dependencies: package.optional_dependencies.to_owned(),
+            dist: NpmVersionDist {
+                shasum: package.resolution.shasum.to_owned(),
+                tarball: package.resolution.tarball_url(&package.name, config),
+                integrity: package.resolution.integrity.to_owned(),
+                file_count: None,
+                unpacked_size: None,
+                npm_signatures: None,
+                signatures: None,
+            },
+            engines: package.engines.to_owned(),
+            deprecated: package.deprecated.to_owned(),
+            has_shrinkwrap: package.has_shrinkwrap,
+        }
+    }
+}
+
+pub fn get_package_key(name: &String, version: &Version) -> String {
+    format!("{name}@{version}")
+}
+
+/// Read the lockfile of the current project, if there is one.
+pub async fn read_lockfile() -> anyhow::Result<Option<Lockfile>> {
+    task::spawn_blocking(|| match fs::read_to_string(LOCKFILE_NAME) {
+        Ok(content) => Ok(Some(serde_json::from_str(&content)?)),
+        Err(error) if error.kind() == ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(error.into()),
+    })
+    .await?
+}
+
+/// Read the locked versions of the current project.
+/// Nothing is reused if the resolution settings changed since the lockfile was written,
+/// as the locked versions may not be the ones these settings pick,
+/// or their dependencies may no longer be accurate.
+pub async fn read_locked_versions(config: &Config) -> anyhow::Result<LockedVersions> {
+    Ok(match read_lockfile().await? {
+        Some(lockfile) if lockfile.settings == LockfileSettings::new(config) => {
+            lockfile.locked_versions(config)
+        }
+        _ => LockedVersions::default(),
+    })
+}
+
+pub async fn write_lockfile(lockfile: Lockfile) -> anyhow::Result<()> {
+    task::spawn_blocking(move || {
+        let mut content = serde_json::to_string_pretty(&lockfile)?;
+        content.push('\n');
+
+        fs::write(LOCKFILE_NAME, content)
+    })
+    .await??;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::npmrc::Npmrc;
+
+    use super::*;
+
+    #[test]
+    fn records_before_setting() {
+        let config = Config {
+            before: Some("2022-06-19T00:00:00Z".parse().unwrap()),
+            ..Default::default()
+        };
+
+        let is_odd = NpmPackageVersion::for_test("is-odd", "0.1.2");
+        let is_even = NpmPackageVersion::for_test("is-even", "1.0.0");
+        let mut graph = DependencyGraph::new();
+        let is_even_id = graph.add_node(is_even.clone(), true);
+        let is_odd_id = graph.add_node(is_odd, false);
+        graph.add_edge(is_even_id, is_odd_id);
+
+        let lockfile = Lockfile::new(&graph, &config);
+
+        assert_eq!(lockfile.settings.before, config.before);
+        assert_eq!(
+            lockfile.dependencies,
+            BTreeMap::from([(is_even.name, is_even.version)])
+        );
+        assert_eq!(
+            lockfile
+                .packages
+                .keys()
+                .map(String::as_str)
+                .collect::<Vec<_>>(),
+            vec!["is-even@1.0.0", "is-odd@0.1.2"]
+        );
+
+        let json = serde_json::to_value(&lockfile).unwrap();
+        assert_eq!(json["settings"]["before"], "2022-06-19T00:00:00Z");
+    }
+
+    #[test]
+    fn records_resolution_settings() {
+        let config = Config {
+            minimum_release_age: Some(chrono::Duration::days(1)),
+            resolution_mode: ResolutionMode::Lowest,
+            ..Default::default()
+        };
+
+        let settings = LockfileSettings::new(&config);
+        assert_ne!(settings, LockfileSettings::new(&Config::default()));
+        assert_eq!(
+            serde_json::to_value(&settings).unwrap(),
+            serde_json::json!({ "minimumReleaseAge": 1440, "resolutionMode": "lowest" })
+        );
+    }
+
+    #[test]
+    fn stores_registry_relative_tarballs() {
+        let mut graph = DependencyGraph::new();
+        graph.add_node(NpmPackageVersion::for_test("is-odd", "0.1.2"), true);
+
+        let lockfile = Lockfile::new(&graph, &Config::default());
+        assert_eq!(
+            *lockfile.packages["is-odd@0.1.2"].resolution.tarball,
+            "is-odd/-/is-odd-0.1.2.tgz"
+        );
+
+        let mirror = Config {
+            npmrc: Npmrc::parse("registry=https://mirror.example/npm/"),
+            ..Default::default()
+        };
+        assert_eq!(
+            *lockfile.packages["is-odd@0.1.2"]
+                .resolution
+                .tarball_url("is-odd", &mirror),
+            "https://mirror.example/npm/is-odd/-/is-odd-0.1.2.tgz"
+        );
+    }
+
+    #[test]
+    fn records_fallback_registries() {
+        let config = Config {
+            npmrc: Npmrc::parse(
+                "registry=https://artifactory.example/\nregistry-fallbacks=https://registry.npmjs.org/",
+            ),
+            ..Default::default()
+        };
+        let mut graph = DependencyGraph::new();
+        graph.add_node(NpmPackageVersion::for_test("is-odd", "0.1.2"), true);
+
+        let resolution = &Lockfile::new(&graph, &config).packages["is-odd@0.1.2"].resolution;
+        assert_eq!(
+            resolution.registry.as_deref(),
+            Some("https://registry.npmjs.org/")
+        );
+        assert_eq!(
+            *resolution.tarball_url("is-odd", &config),
+            "https://registry.npmjs.org/is-odd/-/is-odd-0.1.2.tgz"
+        );
+    }
+}
@@ -1,8 +1,19 @@
 #![deny(clippy::pedantic, clippy::cargo)]
+#![allow(clippy::multiple_crate_versions, clippy::cargo_common_metadata)]
 
 use fast_package_manager::{
-    config::Config, install_manifest::install_manifest, install_package::install_package,
-    npm::VersionRangeSpecifier, DEPS_FOLDER, STORE_FOLDER,
+    cacache::Cacache,
+    catalogs::read_catalogs,
+    config::{build_client, parse_before, parse_release_age, Config, ResolutionMode},
+    http::{NetworkConcurrency, NetworkMode, RetryPolicy},
+    install_manifest::install_manifest,
+    install_package::{install_package, remove_package, upgrade_package},
+    metadata_cache::MetadataCache,
+    npm::parse_package_spec,
+    npmrc::read_npmrc,
+    package_extensions::read_package_extensions,
+    registry::select_registry,
+    DEPS_FOLDER, STORE_FOLDER,
 };
 use std::{collections::HashMap, env, fs, io::ErrorKind};
 
@@ -11,22 +22,53 @@ async fn main() -> anyhow::Result<()> {
     let mut args = env::args();
     args.next();
 
-    let mut packages = HashMap::new();
-    while let Some(package_name) = args.next() {
-        packages.insert(
-            package_name,
-            VersionRangeSpecifier::new(String::from("latest")),
-        );
+    let mut command = None;
+    let mut packages = vec![];
+    let mut before = None;
+    let mut minimum_release_age = None;
+    let mut minimum_release_age_exclude = vec![];
+    let mut prefer_non_deprecated = false;
+    let mut resolution_mode = ResolutionMode::default();
+    let mut jsr_registry = None;
+    let mut network_concurrency = None;
+    let mut network_mode = None;
+    let mut keep_tarballs = false;
+    let mut registry_dir = None;
+    while let Some(arg) = args.next() {
+        if let Some(value) = get_flag_value(&arg, "--before", &mut args)? {
+            before = Some(parse_before(&value)?);
+        } else if let Some(value) = get_flag_value(&arg, "--minimum-release-age", &mut args)? {
+            minimum_release_age = Some(parse_release_age(&value)?);
+        } else if let Some(value) =
+            get_flag_value(&arg, "--minimum-release-age-exclude", &mut args)?
+        {
+            minimum_release_age_exclude.push(value);
+        } else if let Some(value) = get_flag_value(&arg, "--resolution-mode", &mut args)? {
+            resolution_mode = value.parse()?;
+        } else if let Some(value) = get_flag_value(&arg, "--jsr-registry", &mut args)? {
+            jsr_registry = Some(value);
+        } else if let Some(value) = get_flag_value(&arg, "--network-concurrency", &mut args)? {
+            network_concurrency = Some(NetworkConcurrency::new(value.parse()?));
+        } else if arg == "--offline" {
+            network_mode = Some(NetworkMode::Offline);
+        } else if arg == "--prefer-offline" {
+            network_mode = Some(NetworkMode::PreferOffline);
+        } else if let Some(value) = get_flag_value(&arg, "--registry-dir", &mut args)? {
+            registry_dir = Some(value);
+        } else if arg == "--keep-tarballs" {
+            keep_tarballs = true;
+        } else if arg == "--prefer-non-deprecated" {
+            prefer_non_deprecated = true;
+        } else if command.is_none()
+            && packages.is_empty()
+            && ["add", "remove", "upgrade"].contains(&arg.as_str())
+        {
+            command = Some(arg);
+        } else {
+            packages.push(arg);
+        }
     }
 
-    match fs::remove_dir_all(STORE_FOLDER) {
-        Err(error) if error.kind() != ErrorKind::AlreadyExists => return Err(error.into()),
-        _ => {}
-    }
-    match fs::remove_dir_all(DEPS_FOLDER) {
-        Err(error) if error.kind() != ErrorKind::AlreadyExists => return Err(error.into()),
-        _ => {}
-    }
     match fs::create_dir_all(STORE_FOLDER) {
         Err(error) if error.kind() != ErrorKind::AlreadyExists => return Err(error.into()),
         _ => {}
@@ -41,26 +83,84 @@ async fn main() -> anyhow::Result<()> {
     //     .next()
     //     .expect("failed to resolve registry.npmjs.org:443");
     // println!("registry ip: {:?}", ip);
-    let client = reqwest::Client::builder()
-        // .resolve("registry.npmjs.org", ip)
-        // .danger_accept_invalid_certs(true)
-        .build()
-        .expect("failed to build reqwest client");
+    let npmrc = read_npmrc().await?;
+    let client = build_client(&npmrc)?;
+    let retry_policy = RetryPolicy::from_npmrc(&npmrc)?;
+    let metadata_cache = MetadataCache::from_npmrc(&npmrc)?;
+    let npm_cache = Cacache::npm_cache(&npmrc);
+    let registry = select_registry(registry_dir, &npmrc);
+    let tarball_cache = Cacache::kept_tarballs(
+        &metadata_cache,
+        keep_tarballs || npmrc.get("keep-tarballs") == Some("true"),
+    );
+    let network_mode = network_mode.unwrap_or_else(|| NetworkMode::from_npmrc(&npmrc));
+    let network_concurrency = match network_concurrency {
+        Some(network_concurrency) => network_concurrency,
+        None => NetworkConcurrency::from_npmrc(&npmrc)?,
+    };
 
     let config = Config {
         client,
         // npm_registry_ip: ip,
+        before,
+        minimum_release_age,
+        minimum_release_age_exclude,
+        prefer_non_deprecated,
+        resolution_mode,
+        package_extensions: read_package_extensions().await?,
+        catalogs: read_catalogs().await?,
+        jsr_registry,
+        npmrc,
+        retry_policy,
+        network_concurrency,
+        metadata_cache,
+        network_mode,
+        npm_cache,
+        registry,
+        tarball_cache,
     };
 
     // let package = &String::from("uuid");
     // let pac = get_npm_package(package, &config).await?;
 
     // println!("{}", serde_json::to_string_pretty(&pac).unwrap());
-    if packages.len() == 0 {
-        install_manifest(&config).await?;
-    } else {
-        install_package(packages, &config).await?;
+    run_command(command.as_deref(), packages, &config).await
+}
+
+/// Run `command` on `packages`, or install the manifest when there's neither.
+async fn run_command(
+    command: Option<&str>,
+    packages: Vec<String>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    match command {
+        Some("remove") => remove_package(packages, config).await?,
+        Some("upgrade") => upgrade_package(packages, config).await?,
+        _ if packages.is_empty() => install_manifest(config).await?,
+        _ => {
+            let packages: HashMap<_, _> = packages
+                .iter()
+                .map(|package| parse_package_spec(package))
+                .collect();
+            install_package(packages, config).await?;
+        }
     }
 
     Ok(())
 }
+
+/// Get the value of a `--flag=value` or `--flag value` argument, if `arg` is `flag`.
+fn get_flag_value(
+    arg: &str,
+    flag: &str,
+    args: &mut impl Iterator<Item = String>,
+) -> anyhow::Result<Option<String>> {
+    match arg.strip_prefix(flag) {
+        Some("") => match args.next() {
+            Some(value) => Ok(Some(value)),
+            None => Err(anyhow::anyhow!("{flag} requires a value")),
+        },
+        Some(value) => Ok(value.strip_prefix('=').map(ToString::to_string)),
+        None => Ok(None),
+    }
+}
@@ -0,0 +1,180 @@
+use std::{env, path::PathBuf};
+
+use chrono::{DateTime, Duration, Utc};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use crate::{config::parse_release_age, npmrc::Npmrc};
+
+/// Validators and fetch time of a cached packument.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct CacheHeaders {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<String>,
+    pub fetched_at: DateTime<Utc>,
+}
+
+/// A packument as it was received from the registry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CachedPackument {
+    pub headers: CacheHeaders,
+    pub body: String,
+}
+
+/// Packuments cached on disk per registry and package, in `cache-dir`.
+/// Entries younger than `metadata-cache-max-age` are used without a request,
+/// older ones are revalidated with `If-None-Match` and `If-Modified-Since`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MetadataCache {
+    /// Caching is disabled without a directory.
+    pub dir: Option<PathBuf>,
+    pub max_age: Duration,
+}
+
+impl MetadataCache {
+    /// Read `cache-dir`, defaulting to `$XDG_CACHE_HOME/fpm` or `~/.cache/fpm`,
+    /// and `metadata-cache-max-age` in the format of `--minimum-release-age`.
+    pub fn from_npmrc(npmrc: &Npmrc) -> anyhow::Result<Self> {
+        let dir = npmrc.get("cache-dir").map(PathBuf::from).or_else(|| {
+            env::var_os("XDG_CACHE_HOME")
+                .map(PathBuf::from)
+                .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+                .map(|cache| cache.join("fpm"))
+        });
+
+        let max_age = match npmrc.get("metadata-cache-max-age") {
+            Some(max_age) => parse_release_age(max_age)?,
+            None => Duration::zero(),
+        };
+
+        Ok(Self { dir, max_age })
+    }
+
+    /// Where the packument at `url` is cached. Full and abbreviated packuments are kept apart.
+    fn path(&self, url: &Url, full: bool) -> Option<PathBuf> {
+        let folder = if full { "metadata-full" } else { "metadata" };
+        let host = match url.port() {
+            Some(port) => format!("{}_{port}", url.host_str()?),
+            None => url.host_str()?.to_string(),
+        };
+
+        Some(
+            self.dir
+                .as_ref()?
+                .join(folder)
+                .join(host)
+                .join(format!("{}.json", url.path().trim_start_matches('/'))),
+        )
+    }
+
+    /// Get the cached packument at `url`. Unreadable entries count as missing.
+    pub async fn read(&self, url: &Url, full: bool) -> Option<CachedPackument> {
+        let content = fs::read_to_string(self.path(url, full)?).await.ok()?;
+        let (headers, body) = content.split_once('\n')?;
+
+        Some(CachedPackument {
+            headers: serde_json::from_str(headers).ok()?,
+            body: body.to_string(),
+        })
+    }
+
+    /// Cache the packument at `url`, replacing any previous entry atomically.
+    pub async fn write(
+        &self,
+        url: &Url,
+        full: bool,
+        packument: &CachedPackument,
+    ) -> anyhow::Result<()> {
+        let Some(path) = self.path(url, full) else {
+            return Ok(());
+        };
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+
+        let headers = serde_json::to_string(&packument.headers)?;
+        let temp_path = path.with_extension(format!("json.{}", rand::random::<u32>()));
+        fs::write(&temp_path, format!("{headers}\n{}", packument.body)).await?;
+
+        if let Err(error) = fs::rename(&temp_path, &path).await {
+            let _ = fs::remove_file(&temp_path).await;
+            return Err(error.into());
+        }
+
+        Ok(())
+    }
+
+    /// Whether `packument` can be used without revalidating it.
+    pub fn is_fresh(&self, packument: &CachedPackument) -> bool {
+        Utc::now() - packument.headers.fetched_at < self.max_age
+    }
+}
+
+impl CachedPackument {
+    /// Build the entry for a `200` response, keeping its validators.
+    pub fn from_response(headers: &reqwest::header::HeaderMap, body: String) -> Self {
+        let header = |name| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+
+        Self {
+            headers: CacheHeaders {
+                etag: header(reqwest::header::ETAG),
+                last_modified: header(reqwest::header::LAST_MODIFIED),
+                fetched_at: Utc::now(),
+            },
+            body,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn round_trips_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = MetadataCache {
+            dir: Some(dir.path().to_path_buf()),
+            max_age: Duration::minutes(5),
+        };
+        let url = Url::parse("http://localhost:4873/private/@scope/a").unwrap();
+        let packument = CachedPackument {
+            headers: CacheHeaders {
+                etag: Some(String::from("\"v1\"")),
+                last_modified: None,
+                fetched_at: Utc::now(),
+            },
+            body: String::from("{\n  \"name\": \"@scope/a\"\n}"),
+        };
+
+        assert_eq!(cache.read(&url, false).await, None);
+        cache.write(&url, false, &packument).await.unwrap();
+
+        assert!(dir
+            .path()
+            .join("metadata/localhost_4873/private/@scope/a.json")
+            .exists());
+        assert_eq!(cache.read(&url, false).await, Some(packument.clone()));
+        assert_eq!(cache.read(&url, true).await, None);
+        assert!(cache.is_fresh(&packument));
+
+        let stale = CachedPackument {
+            headers: CacheHeaders {
+                fetched_at: Utc::now() - Duration::minutes(10),
+                ..packument.headers
+            },
+            ..packument
+        };
+        assert!(!cache.is_fresh(&stale));
+    }
+}
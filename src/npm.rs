@@ -1,16 +1,19 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
+use chrono::{DateTime, Utc};
 use derive_more::{Deref, Display, Into};
 use indexmap::IndexMap;
 use serde::{Deserialize, Serialize};
 
+use crate::jsr::JSR_PROTOCOL;
+
 #[derive(Debug)]
 pub struct NpmPackage {
     pub json: serde_json::Value,
     pub parsed: NpmResolvedPackage,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct NpmResolvedPackage {
     pub name: String,
 
@@ -18,7 +21,20 @@ pub struct NpmResolvedPackage {
     pub dist_tags: HashMap<String, Version>,
 
     pub versions: IndexMap<Version, NpmPackageVersion>,
+
+    #[serde(default)]
     pub modified: String,
+
+    /// Publish timestamps keyed by version, only present in the full packument.
+    #[serde(default)]
+    pub time: HashMap<String, serde_json::Value>,
+}
+
+impl NpmResolvedPackage {
+    /// Get the time at which `version` was published, if the packument contains it.
+    pub fn published_at(&self, version: &Version) -> Option<DateTime<Utc>> {
+        self.time.get(version.as_str())?.as_str()?.parse().ok()
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone)]
@@ -26,10 +42,68 @@ pub struct NpmPackageVersion {
     pub name: String,
     pub version: Version,
 
-    #[serde(default = "HashMap::new")]
-    pub dependencies: HashMap<String, VersionRangeSpecifier>,
+    #[serde(default = "BTreeMap::new")]
+    pub dependencies: BTreeMap<String, VersionRangeSpecifier>,
+    /// Optional dependencies added by package extensions, skipped when they can't be resolved.
+    /// The optional dependencies packages publish themselves aren't installed.
+    #[serde(skip)]
+    pub optional_dependencies: BTreeMap<String, VersionRangeSpecifier>,
     pub dist: NpmVersionDist,
     pub engines: Option<Engines>,
+
+    /// The deprecation message of this version, if it was deprecated.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_deprecated",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub deprecated: Option<String>,
+
+    /// Whether the package publishes an `npm-shrinkwrap.json` pinning its dependencies.
+    #[serde(
+        default,
+        rename = "_hasShrinkwrap",
+        skip_serializing_if = "std::ops::Not::not"
+    )]
+    pub has_shrinkwrap: bool,
+}
+
+#[cfg(test)]
+impl NpmPackageVersion {
+    /// A published `name@version` without dependencies, served from the npm registry.
+    pub fn for_test(name: &str, version: &str) -> Self {
+        serde_json::from_value(serde_json::json!({
+            "name": name,
+            "version": version,
+            "dist": {
+                "shasum": "",
+                "tarball": format!("https://registry.npmjs.org/{name}/-/{name}-{version}.tgz")
+            }
+        }))
+        .unwrap()
+    }
+}
+
+const DEFAULT_DEPRECATION_MESSAGE: &str = "deprecated";
+
+/// Registries sometimes mark versions as `"deprecated": false` rather than omitting the field,
+/// and `"deprecated": true` without a message.
+fn deserialize_deprecated<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Deprecated {
+        Message(String),
+        Flag(bool),
+    }
+
+    Ok(match Option::<Deprecated>::deserialize(deserializer)? {
+        Some(Deprecated::Message(message)) => Some(message),
+        Some(Deprecated::Flag(true)) => Some(String::from(DEFAULT_DEPRECATION_MESSAGE)),
+        Some(Deprecated::Flag(false)) | None => None,
+    })
 }
 
 #[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone)]
@@ -88,7 +162,9 @@ impl std::error::Error for VersionParseError {}
 
 /// A semver-compatible version range.
 /// Can be either a range - ">3.0.0", "1.2.4" or tag - "latest".
-#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Hash, Eq, Deref, Into)]
+#[derive(
+    Debug, Clone, Deserialize, Serialize, PartialEq, Hash, Eq, PartialOrd, Ord, Deref, Into,
+)]
 #[serde(try_from = "String", into = "String")]
 pub struct VersionRangeSpecifier(String);
 
@@ -111,6 +187,29 @@ pub enum VersionRangeSpecifierParseError {
 }
 impl std::error::Error for VersionRangeSpecifierParseError {}
 
+/// Split a `name@range` package spec, such as `@types/node@^18.0.0`.
+/// The range defaults to `latest` when omitted.
+pub fn parse_package_spec(spec: &str) -> (String, VersionRangeSpecifier) {
+    if let Some(jsr_spec) = spec.strip_prefix(JSR_PROTOCOL) {
+        let (name, range) = parse_package_spec(jsr_spec);
+        return (
+            name,
+            VersionRangeSpecifier::new(format!("{JSR_PROTOCOL}{}", range.as_str())),
+        );
+    }
+
+    match spec.rfind('@') {
+        Some(index) if index > 0 => (
+            spec[..index].to_string(),
+            VersionRangeSpecifier::new(spec[index + 1..].to_string()),
+        ),
+        _ => (
+            spec.to_string(),
+            VersionRangeSpecifier::new(String::from("latest")),
+        ),
+    }
+}
+
 /// A string containing fully-formed URL.
 #[derive(Display, Debug, Clone, Deserialize, Serialize, PartialEq, Hash, Eq, Deref, Into)]
 #[serde(try_from = "String", into = "String")]
@@ -129,52 +228,6 @@ impl TryFrom<String> for UrlString {
     }
 }
 
-/// A dependency tree that represents the concrete versions that packages depend on
-/// and that should be downloaded.
-#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
-pub struct ResolvedDependencyTree {
-    pub name: String,
-    pub version: NpmPackageVersion,
-    pub dependencies: Vec<ResolvedDependencyTree>,
-}
-
-impl ResolvedDependencyTree {
-    pub fn new(
-        name: String,
-        version: NpmPackageVersion,
-        dependencies: Vec<ResolvedDependencyTree>,
-    ) -> Self {
-        Self {
-            name,
-            version,
-            dependencies,
-        }
-    }
-}
-
-/// A dependency tree that represents the concrete versions that packages depend on
-/// and that should be downloaded.
-#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
-pub struct ResolvedDependencies {
-    pub version: NpmPackageVersion,
-    pub dependencies: Vec<NpmPackageVersion>,
-    pub is_root: bool,
-}
-
-impl ResolvedDependencies {
-    pub fn new(
-        version: NpmPackageVersion,
-        dependencies: Vec<NpmPackageVersion>,
-        is_root: bool,
-    ) -> Self {
-        Self {
-            version,
-            dependencies,
-            is_root,
-        }
-    }
-}
-
 #[derive(Debug, PartialEq, Display)]
 pub enum UrlStringParseError {
     InvalidFormat,
@@ -211,4 +264,82 @@ mod tests {
             VersionRangeSpecifier::new(String::from(">1.0.0"))
         )
     }
+
+    #[test]
+    fn version_deprecated() {
+        let version: NpmPackageVersion = serde_json::from_str(
+            r#"{
+                "name": "request",
+                "version": "2.88.2",
+                "dist": { "shasum": "a", "tarball": "https://registry.npmjs.org/request/-/request-2.88.2.tgz" },
+                "deprecated": "request has been deprecated"
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            version.deprecated,
+            Some(String::from("request has been deprecated"))
+        );
+
+        let version: NpmPackageVersion = serde_json::from_str(
+            r#"{
+                "name": "request",
+                "version": "2.88.0",
+                "dist": { "shasum": "a", "tarball": "https://registry.npmjs.org/request/-/request-2.88.0.tgz" },
+                "deprecated": false
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(version.deprecated, None);
+
+        let version: NpmPackageVersion = serde_json::from_str(
+            r#"{
+                "name": "request",
+                "version": "2.87.0",
+                "dist": { "shasum": "a", "tarball": "https://registry.npmjs.org/request/-/request-2.87.0.tgz" },
+                "deprecated": true
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(version.deprecated.as_deref(), Some("deprecated"));
+    }
+
+    #[test]
+    fn package_spec() {
+        assert_eq!(
+            parse_package_spec("lodash"),
+            (
+                String::from("lodash"),
+                VersionRangeSpecifier::new(String::from("latest"))
+            )
+        );
+        assert_eq!(
+            parse_package_spec("lodash@^4.0.0"),
+            (
+                String::from("lodash"),
+                VersionRangeSpecifier::new(String::from("^4.0.0"))
+            )
+        );
+        assert_eq!(
+            parse_package_spec("@types/node"),
+            (
+                String::from("@types/node"),
+                VersionRangeSpecifier::new(String::from("latest"))
+            )
+        );
+        assert_eq!(
+            parse_package_spec("@types/node@18"),
+            (
+                String::from("@types/node"),
+                VersionRangeSpecifier::new(String::from("18"))
+            )
+        );
+        assert_eq!(
+            parse_package_spec("jsr:@std/path@^1.0.0"),
+            (
+                String::from("@std/path"),
+                VersionRangeSpecifier::new(String::from("jsr:^1.0.0"))
+            )
+        );
+    }
 }
@@ -0,0 +1,436 @@
+use std::{collections::BTreeMap, env, fmt, io::ErrorKind, path::PathBuf};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use reqwest::Url;
+use thiserror::Error;
+use tokio::{fs, task};
+
+use crate::package_manifest::{get_manifest_path, ManifestError};
+
+pub const NPMRC_NAME: &str = ".npmrc";
+pub const DEFAULT_REGISTRY_URL: &str = "https://registry.npmjs.org/";
+const NPM_CONFIG_ENV_PREFIX: &str = "npm_config_";
+
+/// Keys holding credentials, either on their own or after a `//host/path/:` prefix.
+const CREDENTIAL_KEYS: [&str; 4] = ["_authToken", "_auth", "username", "_password"];
+
+#[derive(Error, Debug, PartialEq)]
+pub enum NpmrcError {
+    #[error("_password of {0} is not base64 encoded")]
+    InvalidPassword(String),
+}
+
+/// Settings read from `.npmrc` files, with `${ENV}` references already replaced.
+#[derive(Default, Clone, PartialEq, Eq)]
+pub struct Npmrc {
+    entries: BTreeMap<String, String>,
+}
+
+/// Credentials are redacted so they never end up in logs.
+impl fmt::Debug for Npmrc {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let entries = self.entries.iter().map(|(key, value)| {
+            let is_credential = CREDENTIAL_KEYS
+                .iter()
+                .any(|credential| key.rsplit(':').next() == Some(credential));
+            (key, if is_credential { "(redacted)" } else { value })
+        });
+
+        formatter
+            .debug_struct("Npmrc")
+            .field("entries", &BTreeMap::from_iter(entries))
+            .finish()
+    }
+}
+
+impl Npmrc {
+    /// Parse the `key = value` lines of an `.npmrc` file.
+    /// Comments start with `#` or `;`, and `[section]` headers are ignored like npm does.
+    pub fn parse(content: &str) -> Self {
+        let mut entries = BTreeMap::new();
+
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(['#', ';', '[']) {
+                continue;
+            }
+
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|value| value.strip_suffix('"'))
+                .unwrap_or(value);
+
+            entries.insert(interpolate_env(key.trim()), interpolate_env(value));
+        }
+
+        Self { entries }
+    }
+
+    /// Read `npm_config_<key>` environment variables, with `_` standing for `-` in keys
+    /// except for a leading one, so `npm_config__auth` sets `_auth` like it does with npm.
+    pub fn from_env(vars: impl IntoIterator<Item = (String, String)>) -> Self {
+        let entries = vars
+            .into_iter()
+            .filter_map(|(name, value)| {
+                let key = name
+                    .get(..NPM_CONFIG_ENV_PREFIX.len())
+                    .filter(|prefix| prefix.eq_ignore_ascii_case(NPM_CONFIG_ENV_PREFIX))
+                    .map(|_| &name[NPM_CONFIG_ENV_PREFIX.len()..])?;
+
+                let key = key.to_lowercase();
+                let key = match key.strip_prefix('_') {
+                    Some(rest) => format!("_{}", rest.replace('_', "-")),
+                    None => key.replace('_', "-"),
+                };
+
+                Some((key, value))
+            })
+            .filter(|(key, _)| !key.is_empty())
+            .collect();
+
+        Self { entries }
+    }
+
+    /// Add the entries of `other`, overriding any existing ones.
+    pub fn extend(&mut self, other: Npmrc) {
+        self.entries.extend(other.entries);
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries.get(key).map(String::as_str)
+    }
+
+    /// The registry `package_name` is fetched from, either the one of its `@scope:registry`
+    /// or the default `registry`. Always ends with a `/`.
+    pub fn registry(&self, package_name: &str) -> String {
+        let registry = self
+            .scoped(package_name, "registry")
+            .or_else(|| self.get("registry"))
+            .unwrap_or(DEFAULT_REGISTRY_URL);
+
+        with_trailing_slash(registry)
+    }
+
+    /// The registries `package_name` is fetched from, in the order they are tried:
+    /// its `registry`, then the ones listed in `registry-fallbacks`, separated by commas or spaces.
+    /// Packages with an `@scope:registry` only fall back to its `@scope:registry-fallbacks`,
+    /// so that private packages are never looked up on other registries.
+    pub fn registries(&self, package_name: &str) -> Vec<String> {
+        let fallbacks = match self.scoped(package_name, "registry") {
+            Some(_) => self.scoped(package_name, "registry-fallbacks"),
+            None => self.get("registry-fallbacks"),
+        };
+
+        let mut registries = vec![self.registry(package_name)];
+        for fallback in fallbacks
+            .unwrap_or_default()
+            .split([',', ' '])
+            .filter(|fallback| !fallback.is_empty())
+            .map(with_trailing_slash)
+        {
+            if !registries.contains(&fallback) {
+                registries.push(fallback);
+            }
+        }
+
+        registries
+    }
+
+    /// The `@scope:<key>` setting of the scope of `package_name`.
+    fn scoped(&self, package_name: &str, key: &str) -> Option<&str> {
+        let (scope, _) = package_name.strip_prefix('@')?.split_once('/')?;
+        self.get(&format!("@{scope}:{key}"))
+    }
+
+    /// The `Authorization` header to send with a request to `url`.
+    ///
+    /// Credentials are configured per registry as `//host/path/:_authToken`, and apply to
+    /// every URL under that host and path, so tarballs hosted elsewhere never receive them.
+    /// Unprefixed credentials only apply to the default registry.
+    /// Basic credentials from `_auth` or `username` and `_password` are sent as well,
+    /// since registries requiring them reject anonymous requests.
+    pub fn authorization(&self, url: &Url) -> Result<Option<String>, NpmrcError> {
+        let Some(url_dart) = nerf_dart(url) else {
+            return Ok(None);
+        };
+        let default_dart = Url::parse(&self.registry(""))
+            .ok()
+            .and_then(|url| nerf_dart(&url));
+
+        // Try `//host/a/b/`, then `//host/a/`, then `//host/`.
+        let mut dart = url_dart.as_str();
+        loop {
+            let get = |key: &str| {
+                self.get(&format!("{dart}:{key}")).or_else(|| {
+                    (default_dart.as_deref() == Some(dart))
+                        .then(|| self.get(key))
+                        .flatten()
+                })
+            };
+
+            if let Some(token) = get("_authToken") {
+                return Ok(Some(format!("Bearer {token}")));
+            }
+
+            if let Some(auth) = get("_auth") {
+                return Ok(Some(format!("Basic {auth}")));
+            }
+
+            if let (Some(username), Some(password)) = (get("username"), get("_password")) {
+                let password = STANDARD
+                    .decode(password)
+                    .ok()
+                    .and_then(|password| String::from_utf8(password).ok())
+                    .ok_or_else(|| NpmrcError::InvalidPassword(dart.to_string()))?;
+                return Ok(Some(format!(
+                    "Basic {}",
+                    STANDARD.encode(format!("{username}:{password}"))
+                )));
+            }
+
+            let Some(parent) = dart[..dart.len() - 1].rfind('/') else {
+                return Ok(None);
+            };
+            if parent < 2 {
+                return Ok(None);
+            }
+            dart = &dart[..=parent];
+        }
+    }
+}
+
+fn with_trailing_slash(url: &str) -> String {
+    if url.ends_with('/') {
+        url.to_string()
+    } else {
+        format!("{url}/")
+    }
+}
+
+/// The `//host[:port]/path/` form of a URL that npm keys credentials by.
+fn nerf_dart(url: &Url) -> Option<String> {
+    let host = url.host_str()?;
+    let port = url
+        .port()
+        .map(|port| format!(":{port}"))
+        .unwrap_or_default();
+    let path = match url.path().rfind('/') {
+        Some(index) => &url.path()[..=index],
+        None => "/",
+    };
+
+    Some(format!("//{host}{port}{path}"))
+}
+
+/// Replace `${NAME}` with the environment variable `NAME`.
+/// Unset variables are left as is, unless written `${NAME?}` which makes them empty.
+fn interpolate_env(value: &str) -> String {
+    let mut result = String::with_capacity(value.len());
+    let mut rest = value;
+
+    while let Some(start) = rest.find("${") {
+        let Some(end) = rest[start..].find('}') else {
+            break;
+        };
+
+        result.push_str(&rest[..start]);
+        let reference = &rest[start..start + end + 1];
+        let name = &reference[2..reference.len() - 1];
+
+        match (name.strip_suffix('?'), env::var(name.trim_end_matches('?'))) {
+            (_, Ok(value)) => result.push_str(&value),
+            (Some(_), Err(_)) => {}
+            (None, Err(_)) => result.push_str(reference),
+        }
+
+        rest = &rest[start + end + 1..];
+    }
+
+    result.push_str(rest);
+    result
+}
+
+/// Read the global, user and project `.npmrc` files, later ones taking precedence,
+/// where the project file is the one next to the nearest `package.json`,
+/// and then the `npm_config_*` environment variables which override all of them.
+/// The global and user files can be moved with `NPM_CONFIG_GLOBALCONFIG` and `NPM_CONFIG_USERCONFIG`.
+pub async fn read_npmrc() -> anyhow::Result<Npmrc> {
+    let global = env::var_os("NPM_CONFIG_GLOBALCONFIG")
+        .map_or_else(|| PathBuf::from("/usr/local/etc/npmrc"), PathBuf::from);
+    let user = env::var_os("NPM_CONFIG_USERCONFIG")
+        .map(PathBuf::from)
+        .or_else(|| {
+            env::var_os("HOME")
+                .or_else(|| env::var_os("USERPROFILE"))
+                .map(|home| PathBuf::from(home).join(NPMRC_NAME))
+        });
+    // The project file sits next to the manifest, which may be in a parent directory.
+    let project = match task::spawn_blocking(get_manifest_path).await? {
+        Ok(manifest_path) => manifest_path.with_file_name(NPMRC_NAME),
+        Err(error) if error.downcast_ref::<ManifestError>().is_some() => PathBuf::from(NPMRC_NAME),
+        Err(error) => return Err(error),
+    };
+
+    let mut npmrc = Npmrc::default();
+    for path in [Some(global), user, Some(project)].into_iter().flatten() {
+        match fs::read_to_string(&path).await {
+            Ok(content) => npmrc.extend(Npmrc::parse(&content)),
+            Err(error) if error.kind() == ErrorKind::NotFound => {}
+            Err(error) => return Err(error.into()),
+        }
+    }
+
+    npmrc.extend(Npmrc::from_env(env::vars()));
+
+    Ok(npmrc)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_entries() {
+        env::set_var("FPM_TEST_NPMRC_REGISTRY", "npm.ourcompany.com");
+
+        let npmrc = Npmrc::parse(
+            r#"
+            # comment
+            ; another comment
+            registry = https://${FPM_TEST_NPMRC_REGISTRY}/npm
+            @ourcompany:registry="https://${FPM_TEST_NPMRC_REGISTRY}/private/"
+            unset=${FPM_TEST_NPMRC_UNSET}
+            optional=a${FPM_TEST_NPMRC_UNSET?}b
+            "#,
+        );
+
+        assert_eq!(npmrc.get("unset"), Some("${FPM_TEST_NPMRC_UNSET}"));
+        assert_eq!(npmrc.get("optional"), Some("ab"));
+        assert_eq!(npmrc.registry("uuid"), "https://npm.ourcompany.com/npm/");
+        assert_eq!(
+            npmrc.registry("@ourcompany/ui"),
+            "https://npm.ourcompany.com/private/"
+        );
+        assert_eq!(
+            npmrc.registry("@types/node"),
+            "https://npm.ourcompany.com/npm/"
+        );
+        assert_eq!(Npmrc::default().registry("uuid"), DEFAULT_REGISTRY_URL);
+    }
+
+    #[test]
+    fn lists_registry_fallbacks() {
+        let npmrc = Npmrc::parse(
+            r#"
+            registry=https://artifactory.example/npm
+            registry-fallbacks=https://registry.npmjs.org/, https://artifactory.example/npm/
+            @ourcompany:registry=https://npm.ourcompany.com/
+            @mirrored:registry=https://a.example/
+            @mirrored:registry-fallbacks=https://b.example
+            "#,
+        );
+
+        assert_eq!(
+            npmrc.registries("uuid"),
+            vec!["https://artifactory.example/npm/", DEFAULT_REGISTRY_URL]
+        );
+        assert_eq!(
+            npmrc.registries("@ourcompany/ui"),
+            vec!["https://npm.ourcompany.com/"]
+        );
+        assert_eq!(
+            npmrc.registries("@mirrored/ui"),
+            vec!["https://a.example/", "https://b.example/"]
+        );
+    }
+
+    #[test]
+    fn authorization_matches_registry_urls() {
+        let npmrc = Npmrc::parse(
+            r#"
+            registry=https://registry.npmjs.org/
+            _authToken=public-token
+            @ourcompany:registry=https://npm.ourcompany.com/private/
+            //npm.ourcompany.com/private/:_authToken=private-token
+            //basic.example/:username=user
+            //basic.example/:_password=cGFzcw==
+            //auth.example/:_auth=dXNlcjpwYXNz
+            //invalid.example/:username=user
+            //invalid.example/:_password=not base64
+            "#,
+        );
+        let authorization = |url: &str| npmrc.authorization(&Url::parse(url).unwrap()).unwrap();
+
+        assert_eq!(
+            authorization("https://npm.ourcompany.com/private/@ourcompany%2fui"),
+            Some(String::from("Bearer private-token"))
+        );
+        assert_eq!(
+            authorization("https://npm.ourcompany.com/private/@ourcompany/ui/-/ui-1.0.0.tgz"),
+            Some(String::from("Bearer private-token"))
+        );
+        assert_eq!(
+            authorization("https://registry.npmjs.org/uuid"),
+            Some(String::from("Bearer public-token"))
+        );
+        // Tarballs on another host or path never get the token.
+        assert_eq!(authorization("https://cdn.example/ui-1.0.0.tgz"), None);
+        assert_eq!(
+            authorization("https://npm.ourcompany.com/public/uuid"),
+            None
+        );
+        assert_eq!(
+            authorization("https://basic.example/uuid"),
+            Some(String::from("Basic dXNlcjpwYXNz"))
+        );
+        assert_eq!(
+            authorization("https://auth.example/uuid"),
+            Some(String::from("Basic dXNlcjpwYXNz"))
+        );
+        assert_eq!(
+            npmrc.authorization(&Url::parse("https://invalid.example/uuid").unwrap()),
+            Err(NpmrcError::InvalidPassword(String::from(
+                "//invalid.example/"
+            )))
+        );
+        assert!(!format!("{npmrc:?}").contains("private-token"));
+    }
+
+    #[test]
+    fn reads_npm_config_env() {
+        let npmrc = Npmrc::from_env([
+            (
+                String::from("NPM_CONFIG_HTTPS_PROXY"),
+                String::from("http://proxy:3128"),
+            ),
+            (String::from("npm_config_strict_ssl"), String::from("false")),
+            (
+                String::from("npm_config__auth"),
+                String::from("dXNlcjpwYXNz"),
+            ),
+            (
+                String::from("HTTPS_PROXY"),
+                String::from("http://other:3128"),
+            ),
+        ]);
+
+        assert_eq!(npmrc.get("https-proxy"), Some("http://proxy:3128"));
+        assert_eq!(npmrc.get("strict-ssl"), Some("false"));
+        assert_eq!(npmrc.get("_auth"), Some("dXNlcjpwYXNz"));
+        assert_eq!(npmrc.get("proxy"), None);
+    }
+
+    #[test]
+    fn later_files_take_precedence() {
+        let mut npmrc = Npmrc::parse("registry=https://global.example/\nfoo=bar");
+        npmrc.extend(Npmrc::parse("registry=https://project.example/"));
+
+        assert_eq!(npmrc.registry("uuid"), "https://project.example/");
+        assert_eq!(npmrc.get("foo"), Some("bar"));
+    }
+}
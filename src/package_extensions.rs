@@ -0,0 +1,185 @@
+use std::collections::BTreeMap;
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::task;
+
+use crate::{
+    npm::{NpmPackageVersion, VersionRangeSpecifier},
+    package_manifest::{get_manifest_file, ManifestError},
+};
+
+/// Dependencies to add to every package version matching a `name@range` selector,
+/// for packages that forgot to declare them.
+/// Peer dependencies aren't installed, so extensions adding them are rejected
+/// rather than silently ignored.
+#[derive(Deserialize, Serialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct PackageExtension {
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub dependencies: BTreeMap<String, VersionRangeSpecifier>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub optional_dependencies: BTreeMap<String, VersionRangeSpecifier>,
+}
+
+/// Package extensions keyed by a `name@range` selector, or just `name` to match every version.
+pub type PackageExtensions = BTreeMap<String, PackageExtension>;
+
+/// Read the `packageExtensions` of the manifest, either at the top level or under `fpm`.
+pub async fn read_package_extensions() -> anyhow::Result<PackageExtensions> {
+    let manifest = match task::spawn_blocking(get_manifest_file).await? {
+        Ok(manifest) => manifest,
+        Err(error) if error.downcast_ref::<ManifestError>().is_some() => {
+            return Ok(PackageExtensions::new())
+        }
+        Err(error) => return Err(error),
+    };
+
+    let extensions = manifest
+        .get("packageExtensions")
+        .or_else(|| manifest.get("fpm")?.get("packageExtensions"));
+
+    match extensions {
+        Some(extensions) => {
+            Ok(serde_json::from_value(extensions.to_owned())
+                .context("invalid packageExtensions")?)
+        }
+        None => Ok(PackageExtensions::new()),
+    }
+}
+
+/// Add the dependencies of every matching extension to `version`.
+/// Extensions take precedence over the dependencies the package declares.
+pub fn apply_package_extensions(version: &mut NpmPackageVersion, extensions: &PackageExtensions) {
+    for (selector, extension) in extensions {
+        if !matches_selector(selector, version) {
+            continue;
+        }
+
+        version.dependencies.extend(extension.dependencies.clone());
+        version
+            .optional_dependencies
+            .extend(extension.optional_dependencies.clone());
+    }
+}
+
+/// A checksum of the extensions, stored in the lockfile to detect when they change.
+pub fn package_extensions_checksum(extensions: &PackageExtensions) -> Option<String> {
+    if extensions.is_empty() {
+        return None;
+    }
+
+    let serialized = serde_json::to_vec(extensions).unwrap_or_default();
+    Some(format!("sha256-{:x}", Sha256::digest(serialized)))
+}
+
+fn matches_selector(selector: &str, version: &NpmPackageVersion) -> bool {
+    let (name, range) = match selector.rfind('@') {
+        Some(index) if index > 0 => (&selector[..index], &selector[index + 1..]),
+        _ => return selector == version.name,
+    };
+
+    if name != version.name {
+        return false;
+    }
+
+    match (
+        range.parse::<node_semver::Range>(),
+        version.version.parse::<node_semver::Version>(),
+    ) {
+        (Ok(range), Ok(version)) => range.satisfies(&version),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn extensions() -> PackageExtensions {
+        serde_json::from_value(json!({
+            "react-redux@<8": {
+                "dependencies": { "react-dom": "*" }
+            },
+            "webpack": {
+                "dependencies": { "tapable": "^2.0.0" },
+                "optionalDependencies": { "fsevents": "^2.0.0" }
+            }
+        }))
+        .unwrap()
+    }
+
+    fn package_version(name: &str, version: &str) -> NpmPackageVersion {
+        let mut package = NpmPackageVersion::for_test(name, version);
+        package.dependencies.insert(
+            String::from("tapable"),
+            VersionRangeSpecifier::new(String::from("^1.0.0")),
+        );
+        package
+    }
+
+    #[test]
+    fn applies_matching_extensions() {
+        let mut webpack = package_version("webpack", "5.0.0");
+        apply_package_extensions(&mut webpack, &extensions());
+
+        assert_eq!(
+            webpack.dependencies,
+            BTreeMap::from([(
+                String::from("tapable"),
+                VersionRangeSpecifier::new(String::from("^2.0.0"))
+            )])
+        );
+        assert_eq!(
+            webpack.optional_dependencies.keys().collect::<Vec<_>>(),
+            vec!["fsevents"]
+        );
+
+        let mut react_redux = package_version("react-redux", "7.2.9");
+        apply_package_extensions(&mut react_redux, &extensions());
+        assert_eq!(
+            react_redux.dependencies.keys().collect::<Vec<_>>(),
+            vec!["react-dom", "tapable"]
+        );
+
+        let mut react_redux = package_version("react-redux", "8.0.0");
+        apply_package_extensions(&mut react_redux, &extensions());
+        assert_eq!(
+            react_redux.dependencies.keys().collect::<Vec<_>>(),
+            vec!["tapable"]
+        );
+    }
+
+    #[test]
+    fn rejects_unsupported_fields() {
+        let error = serde_json::from_value::<PackageExtensions>(json!({
+            "react-redux@<8": {
+                "peerDependencies": { "react-dom": "*" }
+            }
+        }))
+        .unwrap_err();
+
+        assert!(error
+            .to_string()
+            .contains("unknown field `peerDependencies`"));
+    }
+
+    #[test]
+    fn checksum_changes_with_extensions() {
+        let mut changed = extensions();
+        changed.remove("webpack");
+
+        assert_eq!(package_extensions_checksum(&PackageExtensions::new()), None);
+        assert_eq!(
+            package_extensions_checksum(&extensions()),
+            package_extensions_checksum(&extensions())
+        );
+        assert_ne!(
+            package_extensions_checksum(&extensions()),
+            package_extensions_checksum(&changed)
+        );
+    }
+}
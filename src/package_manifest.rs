@@ -3,8 +3,10 @@ use std::{
     env,
     fs::{self, File},
     io::BufReader,
+    path::{Path, PathBuf},
 };
 
+use anyhow::Context;
 use serde_json::Value;
 use thiserror::Error;
 use tokio::task;
@@ -30,8 +32,8 @@ fn update_manifest(packages_to_add: HashMap<String, VersionRangeSpecifier>) -> a
 
     match &mut package_json {
         Value::Object(package_json) => match package_json.get_mut("dependencies") {
-            Some(deps) => match deps {
-                Value::Object(deps_obj) => {
+            Some(deps) => {
+                if let Value::Object(deps_obj) = deps {
                     for (package, range) in packages_to_add {
                         deps_obj.insert(
                             package.to_owned(),
@@ -39,8 +41,7 @@ fn update_manifest(packages_to_add: HashMap<String, VersionRangeSpecifier>) -> a
                         );
                     }
                 }
-                _ => (),
-            },
+            }
             None => match serde_json::to_string(&packages_to_add) {
                 Ok(deps) => {
                     package_json.insert(String::from("dependencies"), Value::String(deps));
@@ -59,7 +60,53 @@ fn update_manifest(packages_to_add: HashMap<String, VersionRangeSpecifier>) -> a
     Ok(())
 }
 
+pub async fn remove_from_package_manifest(packages_to_remove: Vec<String>) -> anyhow::Result<()> {
+    task::spawn_blocking(|| remove_from_manifest(packages_to_remove)).await??;
+
+    Ok(())
+}
+
+fn remove_from_manifest(packages_to_remove: Vec<String>) -> anyhow::Result<()> {
+    let mut package_json = get_manifest_file()?;
+
+    if let Some(Value::Object(deps)) = package_json.get_mut("dependencies") {
+        for package in packages_to_remove {
+            deps.remove(&package);
+        }
+    }
+
+    fs::write(
+        "./package.json",
+        serde_json::to_string_pretty(&package_json).unwrap(),
+    )?;
+
+    Ok(())
+}
+
+/// Get the direct dependencies declared in the manifest.
+pub async fn get_manifest_dependencies() -> anyhow::Result<HashMap<String, VersionRangeSpecifier>> {
+    let manifest_file = task::spawn_blocking(get_manifest_file).await??;
+
+    parse_dependencies(&manifest_file)
+}
+
+/// The `dependencies` of `manifest`. A malformed `dependencies` field is an error rather than
+/// no dependencies, which would uninstall everything.
+fn parse_dependencies(manifest: &Value) -> anyhow::Result<HashMap<String, VersionRangeSpecifier>> {
+    match manifest.get("dependencies") {
+        Some(deps) => {
+            serde_json::from_value(deps.to_owned()).context("invalid dependencies in package.json")
+        }
+        None => Ok(HashMap::new()),
+    }
+}
+
 pub fn get_manifest_file() -> anyhow::Result<Value> {
+    read_manifest_file(&get_manifest_path()?)
+}
+
+/// Find the nearest `package.json`, in the current directory or one of its ancestors.
+pub fn get_manifest_path() -> anyhow::Result<PathBuf> {
     let mut manifest_path = env::current_dir()?.join("package.json");
 
     while !manifest_path.exists() {
@@ -74,6 +121,41 @@ pub fn get_manifest_file() -> anyhow::Result<Value> {
         }
     }
 
+    Ok(manifest_path)
+}
+
+/// Get the manifest of the workspace root, which is the nearest manifest declaring
+/// `workspaces`, or the nearest manifest if the project is not part of a workspace.
+pub fn get_workspace_manifest_file() -> anyhow::Result<Value> {
+    let nearest = get_manifest_file()?;
+
+    match find_workspace_manifest(&env::current_dir()?)? {
+        Some(workspace_root) => Ok(workspace_root),
+        None => Ok(nearest),
+    }
+}
+
+/// Look for a manifest declaring `workspaces` in `dir` and its ancestors,
+/// without leaving the repository `dir` is in.
+fn find_workspace_manifest(dir: &Path) -> anyhow::Result<Option<Value>> {
+    for dir in dir.ancestors() {
+        let manifest_path = dir.join("package.json");
+        if manifest_path.exists() {
+            let manifest = read_manifest_file(&manifest_path)?;
+            if manifest.get("workspaces").is_some() {
+                return Ok(Some(manifest));
+            }
+        }
+
+        if dir.join(".git").exists() {
+            break;
+        }
+    }
+
+    Ok(None)
+}
+
+fn read_manifest_file(manifest_path: &Path) -> anyhow::Result<Value> {
     let file = File::open(manifest_path)?;
     let reader = BufReader::new(file);
 
@@ -91,6 +173,21 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn rejects_malformed_dependencies() {
+        let dependencies = parse_dependencies(&json!({ "dependencies": { "a": "^1.0.0" } }));
+        assert_eq!(
+            dependencies.unwrap(),
+            HashMap::from([(
+                String::from("a"),
+                VersionRangeSpecifier::new(String::from("^1.0.0"))
+            )])
+        );
+
+        assert!(parse_dependencies(&json!({})).unwrap().is_empty());
+        assert!(parse_dependencies(&json!({ "dependencies": ["a"] })).is_err());
+    }
+
     #[test]
     fn get_manifest_from_pwd() {
         let pwd = std::env::current_dir().unwrap();
@@ -159,4 +256,27 @@ mod tests {
 
         std::env::set_current_dir(pwd).unwrap();
     }
+
+    #[test]
+    fn find_workspace_manifest_stops_at_repository_root() {
+        let outer = tempdir().unwrap();
+        let write = |dir: &Path, content: Value| {
+            std::fs::create_dir_all(dir).unwrap();
+            std::fs::write(dir.join("package.json"), content.to_string()).unwrap();
+        };
+
+        let repository = outer.path().join("repository");
+        let member = repository.join("packages/member");
+        write(outer.path(), json!({ "workspaces": ["*"] }));
+        write(&repository, json!({ "name": "repository" }));
+        write(&member, json!({ "name": "member" }));
+        std::fs::create_dir(repository.join(".git")).unwrap();
+
+        assert_eq!(find_workspace_manifest(&member).unwrap(), None);
+
+        let workspace = json!({ "workspaces": ["packages/*"] });
+        write(&repository.join("packages"), workspace.clone());
+        write(&repository, json!({ "workspaces": ["packages/*/*"] }));
+        assert_eq!(find_workspace_manifest(&member).unwrap(), Some(workspace));
+    }
 }
@@ -0,0 +1,98 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::OnceCell;
+
+use crate::npm::NpmResolvedPackage;
+
+/// Packuments fetched during a resolution, keyed by package name.
+/// Concurrent requests for the same package share a single fetch,
+/// and later ones are served the already parsed packument.
+/// Failed fetches aren't cached, so the next request tries again.
+#[derive(Debug, Default)]
+pub struct PackumentCache {
+    packuments: Mutex<HashMap<String, Arc<OnceCell<Arc<NpmResolvedPackage>>>>>,
+}
+
+impl PackumentCache {
+    pub async fn get_or_fetch<F, Fut>(
+        &self,
+        package_name: &str,
+        fetch: F,
+    ) -> anyhow::Result<Arc<NpmResolvedPackage>>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = anyhow::Result<NpmResolvedPackage>>,
+    {
+        let cell = self
+            .packuments
+            .lock()
+            .expect("packument cache lock poisoned")
+            .entry(package_name.to_string())
+            .or_default()
+            .clone();
+
+        cell.get_or_try_init(|| async { fetch().await.map(Arc::new) })
+            .await
+            .cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use serde_json::json;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn coalesces_concurrent_fetches() {
+        let cache = PackumentCache::default();
+        let fetches = AtomicUsize::new(0);
+        let fetch = || async {
+            fetches.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            Ok(serde_json::from_value(json!({
+                "name": "a",
+                "dist-tags": {},
+                "versions": {}
+            }))?)
+        };
+
+        let (first, second) = tokio::join!(
+            cache.get_or_fetch("a", fetch),
+            cache.get_or_fetch("a", fetch)
+        );
+        assert!(Arc::ptr_eq(&first.unwrap(), &second.unwrap()));
+
+        cache.get_or_fetch("a", fetch).await.unwrap();
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn retries_failed_fetches() {
+        let cache = PackumentCache::default();
+
+        assert!(cache
+            .get_or_fetch("a", || async { Err(anyhow::anyhow!("offline")) })
+            .await
+            .is_err());
+        assert!(cache
+            .get_or_fetch("a", || async {
+                Ok(serde_json::from_value(json!({
+                    "name": "a",
+                    "dist-tags": {},
+                    "versions": {}
+                }))?)
+            })
+            .await
+            .is_ok());
+    }
+}
@@ -0,0 +1,161 @@
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use tokio::fs;
+
+use crate::{
+    config::Config,
+    http::{download_tarball, get_npm_package, get_npm_package_preferring},
+    npm::{NpmResolvedPackage, UrlString},
+    npmrc::Npmrc,
+};
+
+/// Where packuments and tarballs are fetched from.
+#[cfg_attr(test, mockall::automock)]
+#[async_trait]
+pub trait Registry: Send + Sync {
+    /// Get the packument of `name`, from `preferred_registry` first if given,
+    /// which is the registry that served the package before.
+    async fn get_package<'a>(
+        &self,
+        name: &str,
+        preferred_registry: Option<&'a str>,
+        config: &Config,
+    ) -> anyhow::Result<NpmResolvedPackage>;
+
+    /// Write the tarball at `tarball` to `path`.
+    async fn download_tarball(
+        &self,
+        tarball: &UrlString,
+        path: &Path,
+        config: &Config,
+    ) -> anyhow::Result<()>;
+}
+
+/// The folder given with `--registry-dir` or `registry-dir` if any,
+/// or else the registries configured in `.npmrc`.
+pub fn select_registry(registry_dir: Option<String>, npmrc: &Npmrc) -> Box<dyn Registry> {
+    match registry_dir.or_else(|| npmrc.get("registry-dir").map(str::to_string)) {
+        Some(dir) => Box::new(DirectoryRegistry::new(dir)),
+        None => Box::new(HttpRegistry),
+    }
+}
+
+/// The registries configured in `.npmrc`, over HTTP.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct HttpRegistry;
+
+#[async_trait]
+impl Registry for HttpRegistry {
+    async fn get_package<'a>(
+        &self,
+        name: &str,
+        preferred_registry: Option<&'a str>,
+        config: &Config,
+    ) -> anyhow::Result<NpmResolvedPackage> {
+        match preferred_registry {
+            Some(registry) => get_npm_package_preferring(&name.to_string(), registry, config).await,
+            None => get_npm_package(&name.to_string(), config).await,
+        }
+    }
+
+    async fn download_tarball(
+        &self,
+        tarball: &UrlString,
+        path: &Path,
+        config: &Config,
+    ) -> anyhow::Result<()> {
+        download_tarball(tarball, path, config).await
+    }
+}
+
+/// A folder laid out like the registry: the packument of `name` in `<name>.json`,
+/// and tarballs at their path under the registry, like `a/-/a-1.0.0.tgz`.
+/// Lets installs work without any network.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirectoryRegistry {
+    pub dir: PathBuf,
+}
+
+impl DirectoryRegistry {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+}
+
+#[async_trait]
+impl Registry for DirectoryRegistry {
+    async fn get_package<'a>(
+        &self,
+        name: &str,
+        _preferred_registry: Option<&'a str>,
+        _config: &Config,
+    ) -> anyhow::Result<NpmResolvedPackage> {
+        let path = self.dir.join(format!("{name}.json"));
+        let content = fs::read_to_string(&path)
+            .await
+            .map_err(|error| anyhow::anyhow!("failed to read {}: {error}", path.display()))?;
+
+        Ok(serde_json::from_str(&content)?)
+    }
+
+    async fn download_tarball(
+        &self,
+        tarball: &UrlString,
+        path: &Path,
+        config: &Config,
+    ) -> anyhow::Result<()> {
+        // Tarballs on a registry are looked up by their path under it, others by their URL path.
+        let relative = match config.split_tarball_url(tarball) {
+            Some((_, relative)) => relative.to_string(),
+            None => reqwest::Url::parse(tarball)?.path().to_string(),
+        };
+        let source = self.dir.join(relative.trim_start_matches('/'));
+
+        fs::copy(&source, path)
+            .await
+            .map_err(|error| anyhow::anyhow!("failed to read {}: {error}", source.display()))?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reads_packuments_and_tarballs_from_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("@scope/a/-")).unwrap();
+        std::fs::write(
+            dir.path().join("@scope/a.json"),
+            r#"{ "name": "@scope/a", "dist-tags": { "latest": "1.0.0" }, "versions": {} }"#,
+        )
+        .unwrap();
+        std::fs::write(dir.path().join("@scope/a/-/a-1.0.0.tgz"), b"tarball").unwrap();
+
+        let registry = DirectoryRegistry::new(dir.path());
+        let config = Config::default();
+
+        let package = registry
+            .get_package("@scope/a", None, &config)
+            .await
+            .unwrap();
+        assert_eq!(package.name, "@scope/a");
+        assert!(registry.get_package("b", None, &config).await.is_err());
+
+        let destination = dir.path().join("a-1.0.0.tgz.partial");
+        registry
+            .download_tarball(
+                &UrlString::new(String::from(
+                    "https://registry.npmjs.org/@scope/a/-/a-1.0.0.tgz",
+                )),
+                &destination,
+                &config,
+            )
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(destination).unwrap(), b"tarball");
+    }
+}
@@ -1,5 +1,6 @@
 use std::error;
 
+use chrono::{DateTime, Utc};
 use derive_more::Display;
 
 use crate::npm::{NpmPackageVersion, NpmResolvedPackage, Version, VersionRangeSpecifier};
@@ -11,21 +12,53 @@ pub enum Error {
 
 impl error::Error for Error {}
 
+/// Preferences that narrow down which of the matching versions is picked.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ResolveOptions {
+    /// Only consider versions published at or before this time.
+    pub published_before: Option<DateTime<Utc>>,
+    /// Skip deprecated versions, unless no other version matches.
+    pub prefer_non_deprecated: bool,
+    /// Pick the lowest matching version instead of the highest.
+    pub lowest: bool,
+}
+
 /// Get a package and a version range,
 /// and return the matching version. It will return None if the version is not found.
-pub fn resolve_version_from_version_range<'a>(
-    package: &'a NpmResolvedPackage,
+pub fn resolve_version_from_version_range(
+    package: &NpmResolvedPackage,
     version_range: &VersionRangeSpecifier,
+    options: &ResolveOptions,
 ) -> Result<NpmPackageVersion, Error> {
+    let published_before = options.published_before.as_ref();
+
     if *version_range == VersionRangeSpecifier::new(String::from("latest")) {
         let latest = package.dist_tags.get("latest");
 
-        return match latest {
-            Some(latest) => package
+        let is_deprecated = |latest| {
+            package
                 .versions
                 .get(latest)
-                .map(|version| version.to_owned())
-                .ok_or(Error::VersionRangeResolveError),
+                .is_some_and(|version| version.deprecated.is_some())
+        };
+
+        return match latest {
+            Some(latest)
+                if is_published_before(package, latest, published_before)
+                    && !(options.prefer_non_deprecated && is_deprecated(latest)) =>
+            {
+                package
+                    .versions
+                    .get(latest)
+                    .map(|version| version.to_owned())
+                    .ok_or(Error::VersionRangeResolveError)
+            }
+            // The tagged version is too new or deprecated, fall back to the newest older one.
+            Some(latest) => resolve_version_from_version_range(
+                package,
+                &VersionRangeSpecifier::new(format!("<={latest}")),
+                options,
+            ),
             None => Err(Error::VersionRangeResolveError),
         };
     }
@@ -35,25 +68,42 @@ pub fn resolve_version_from_version_range<'a>(
         Err(_) => return Err(Error::VersionRangeResolveError),
     };
 
-    let mut matched_version: Result<NpmPackageVersion, Error> =
-        Err(Error::VersionRangeResolveError);
+    let mut candidates: Vec<(node_semver::Version, &NpmPackageVersion)> = package
+        .versions
+        .iter()
+        .filter(|(version, _)| is_published_before(package, version, published_before))
+        .filter_map(|(version, package_version)| match version.parse() {
+            Ok(parsed_version) if version_req.satisfies(&parsed_version) => {
+                Some((parsed_version, package_version))
+            }
+            _ => None,
+        })
+        .collect();
 
-    for vrs in package.versions.iter().rev() {
-        matched_version = match vrs.0.parse::<node_semver::Version>() {
-            Ok(parsed_version) if parsed_version.satisfies(&version_req) => package
-                .versions
-                .get(&Version::new(vrs.0.to_string()))
-                .map(|version| version.to_owned())
-                .ok_or(Error::VersionRangeResolveError),
-            _ => Err(Error::VersionRangeResolveError),
-        };
-
-        if matched_version.is_ok() {
-            break;
-        }
+    candidates.sort_by(|(a, _), (b, _)| a.cmp(b));
+    if !options.lowest {
+        candidates.reverse();
     }
 
-    matched_version
+    candidates
+        .iter()
+        .find(|(_, version)| !options.prefer_non_deprecated || version.deprecated.is_none())
+        // Fall back to a deprecated version in case no other version matches.
+        .or(candidates.first())
+        .map(|(_, version)| (*version).to_owned())
+        .ok_or(Error::VersionRangeResolveError)
+}
+
+/// Versions without a publish time are never considered to be published before a cutoff.
+fn is_published_before(
+    package: &NpmResolvedPackage,
+    version: &Version,
+    published_before: Option<&DateTime<Utc>>,
+) -> bool {
+    match published_before {
+        Some(cutoff) => matches!(package.published_at(version), Some(time) if time <= *cutoff),
+        None => true,
+    }
 }
 
 #[cfg(test)]
@@ -126,6 +176,7 @@ mod tests {
         let resolved = resolve_version_from_version_range(
             &package,
             &VersionRangeSpecifier::new(String::from("latest")),
+            &ResolveOptions::default(),
         );
 
         assert_eq!(
@@ -199,11 +250,12 @@ mod tests {
             "modified": "2022-06-19T02:40:54.045Z"
           }"#;
 
-        let package: NpmResolvedPackage = serde_json::from_str(&package_json).unwrap();
+        let package: NpmResolvedPackage = serde_json::from_str(package_json).unwrap();
 
         let resolved = resolve_version_from_version_range(
             &package,
             &VersionRangeSpecifier::new(String::from("^0.1.2")),
+            &ResolveOptions::default(),
         );
 
         assert_eq!(
@@ -215,4 +267,243 @@ mod tests {
                 .ok_or(Error::VersionRangeResolveError)
         );
     }
+
+    #[test]
+    fn resolves_semver_published_before() {
+        let package_json = r#"{
+            "name": "is-odd",
+            "dist-tags": {
+              "latest": "0.1.2"
+            },
+            "versions": {
+              "0.1.0": {
+                "name": "is-odd",
+                "version": "0.1.0",
+                "dist": {
+                  "shasum": "a",
+                  "tarball": "https://registry.npmjs.org/is-odd/-/is-odd-0.1.0.tgz"
+                }
+              },
+              "0.1.1": {
+                "name": "is-odd",
+                "version": "0.1.1",
+                "dist": {
+                  "shasum": "b",
+                  "tarball": "https://registry.npmjs.org/is-odd/-/is-odd-0.1.1.tgz"
+                }
+              },
+              "0.1.2": {
+                "name": "is-odd",
+                "version": "0.1.2",
+                "dist": {
+                  "shasum": "c",
+                  "tarball": "https://registry.npmjs.org/is-odd/-/is-odd-0.1.2.tgz"
+                }
+              }
+            },
+            "time": {
+              "created": "2016-01-01T00:00:00.000Z",
+              "modified": "2017-01-01T00:00:00.000Z",
+              "0.1.0": "2016-01-01T00:00:00.000Z",
+              "0.1.1": "2016-06-01T00:00:00.000Z",
+              "0.1.2": "2017-01-01T00:00:00.000Z"
+            }
+          }"#;
+
+        let package: NpmResolvedPackage = serde_json::from_str(package_json).unwrap();
+        let cutoff: DateTime<Utc> = "2016-12-31T00:00:00Z".parse().unwrap();
+
+        let resolved = resolve_version_from_version_range(
+            &package,
+            &VersionRangeSpecifier::new(String::from("^0.1.0")),
+            &ResolveOptions {
+                published_before: Some(cutoff),
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            resolved.map(|version| version.version),
+            Ok(Version::new("0.1.1".to_string()))
+        );
+
+        let resolved = resolve_version_from_version_range(
+            &package,
+            &VersionRangeSpecifier::new(String::from("latest")),
+            &ResolveOptions {
+                published_before: Some(cutoff),
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            resolved.map(|version| version.version),
+            Ok(Version::new("0.1.1".to_string()))
+        );
+
+        let cutoff: DateTime<Utc> = "2015-01-01T00:00:00Z".parse().unwrap();
+        let resolved = resolve_version_from_version_range(
+            &package,
+            &VersionRangeSpecifier::new(String::from("^0.1.0")),
+            &ResolveOptions {
+                published_before: Some(cutoff),
+                ..Default::default()
+            },
+        );
+        assert_eq!(resolved, Err(Error::VersionRangeResolveError));
+    }
+
+    #[test]
+    fn resolves_semver_prefer_non_deprecated() {
+        let package_json = r#"{
+            "name": "is-odd",
+            "dist-tags": {
+              "latest": "0.1.2"
+            },
+            "versions": {
+              "0.1.0": {
+                "name": "is-odd",
+                "version": "0.1.0",
+                "dist": {
+                  "shasum": "a",
+                  "tarball": "https://registry.npmjs.org/is-odd/-/is-odd-0.1.0.tgz"
+                },
+                "deprecated": "this version is broken"
+              },
+              "0.1.1": {
+                "name": "is-odd",
+                "version": "0.1.1",
+                "dist": {
+                  "shasum": "b",
+                  "tarball": "https://registry.npmjs.org/is-odd/-/is-odd-0.1.1.tgz"
+                }
+              },
+              "0.1.2": {
+                "name": "is-odd",
+                "version": "0.1.2",
+                "dist": {
+                  "shasum": "c",
+                  "tarball": "https://registry.npmjs.org/is-odd/-/is-odd-0.1.2.tgz"
+                },
+                "deprecated": "this version is broken"
+              }
+            }
+          }"#;
+
+        let package: NpmResolvedPackage = serde_json::from_str(package_json).unwrap();
+        let options = ResolveOptions {
+            prefer_non_deprecated: true,
+            ..Default::default()
+        };
+
+        let resolved = resolve_version_from_version_range(
+            &package,
+            &VersionRangeSpecifier::new(String::from("^0.1.0")),
+            &ResolveOptions::default(),
+        );
+        assert_eq!(
+            resolved.map(|version| version.version),
+            Ok(Version::new("0.1.2".to_string()))
+        );
+
+        let resolved = resolve_version_from_version_range(
+            &package,
+            &VersionRangeSpecifier::new(String::from("^0.1.0")),
+            &options,
+        );
+        assert_eq!(
+            resolved.map(|version| version.version),
+            Ok(Version::new("0.1.1".to_string()))
+        );
+
+        // `latest` is deprecated, so the newest older version is picked instead.
+        let resolved = resolve_version_from_version_range(
+            &package,
+            &VersionRangeSpecifier::new(String::from("latest")),
+            &options,
+        );
+        assert_eq!(
+            resolved.map(|version| version.version),
+            Ok(Version::new("0.1.1".to_string()))
+        );
+
+        // Falls back to the newest deprecated version when nothing else matches.
+        let resolved = resolve_version_from_version_range(
+            &package,
+            &VersionRangeSpecifier::new(String::from("0.1.0 || 0.1.2")),
+            &options,
+        );
+        assert_eq!(
+            resolved.map(|version| version.version),
+            Ok(Version::new("0.1.2".to_string()))
+        );
+    }
+
+    #[test]
+    fn resolves_semver_lowest() {
+        let package_json = r#"{
+            "name": "is-odd",
+            "dist-tags": {
+              "latest": "1.0.0"
+            },
+            "versions": {
+              "0.1.0": {
+                "name": "is-odd",
+                "version": "0.1.0",
+                "dist": {
+                  "shasum": "a",
+                  "tarball": "https://registry.npmjs.org/is-odd/-/is-odd-0.1.0.tgz"
+                }
+              },
+              "1.0.0": {
+                "name": "is-odd",
+                "version": "1.0.0",
+                "dist": {
+                  "shasum": "b",
+                  "tarball": "https://registry.npmjs.org/is-odd/-/is-odd-1.0.0.tgz"
+                }
+              },
+              "0.1.2": {
+                "name": "is-odd",
+                "version": "0.1.2",
+                "dist": {
+                  "shasum": "c",
+                  "tarball": "https://registry.npmjs.org/is-odd/-/is-odd-0.1.2.tgz"
+                }
+              },
+              "0.1.1": {
+                "name": "is-odd",
+                "version": "0.1.1",
+                "dist": {
+                  "shasum": "d",
+                  "tarball": "https://registry.npmjs.org/is-odd/-/is-odd-0.1.1.tgz"
+                }
+              }
+            }
+          }"#;
+
+        let package: NpmResolvedPackage = serde_json::from_str(package_json).unwrap();
+
+        let resolved = resolve_version_from_version_range(
+            &package,
+            &VersionRangeSpecifier::new(String::from(">=0.1.1")),
+            &ResolveOptions {
+                lowest: true,
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            resolved.map(|version| version.version),
+            Ok(Version::new("0.1.1".to_string()))
+        );
+
+        // Versions published out of order are still compared by semver.
+        let resolved = resolve_version_from_version_range(
+            &package,
+            &VersionRangeSpecifier::new(String::from("^0.1.0")),
+            &ResolveOptions::default(),
+        );
+        assert_eq!(
+            resolved.map(|version| version.version),
+            Ok(Version::new("0.1.2".to_string()))
+        );
+    }
 }
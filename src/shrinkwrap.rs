@@ -0,0 +1,236 @@
+use std::{collections::BTreeMap, io::ErrorKind};
+
+use serde::Deserialize;
+use tokio::fs;
+
+use crate::{
+    config::Config,
+    downloader::{download_package_to_store, get_store_package_path},
+    npm::{NpmPackageVersion, NpmVersionDist, UrlString, Version, VersionRangeSpecifier},
+};
+
+pub const SHRINKWRAP_NAME: &str = "npm-shrinkwrap.json";
+
+/// An `npm-shrinkwrap.json` published inside a package, pinning its dependency tree.
+/// Only the `packages` section of lockfile version 2 and 3 is read,
+/// so older shrinkwraps pin nothing and the manifest ranges are used instead.
+#[derive(Deserialize, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Shrinkwrap {
+    #[serde(default)]
+    pub lockfile_version: u32,
+
+    /// Every package of the tree keyed by its install path, such as `node_modules/a/node_modules/b`.
+    #[serde(default)]
+    pub packages: BTreeMap<String, ShrinkwrapPackage>,
+}
+
+#[derive(Deserialize, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct ShrinkwrapPackage {
+    pub name: Option<String>,
+    pub version: Option<Version>,
+    pub resolved: Option<UrlString>,
+    pub integrity: Option<String>,
+
+    #[serde(default)]
+    pub dependencies: BTreeMap<String, VersionRangeSpecifier>,
+
+    #[serde(default)]
+    pub dev: bool,
+    #[serde(default)]
+    pub link: bool,
+}
+
+impl Shrinkwrap {
+    /// Pin the dependencies of `version`, the package that published the shrinkwrap,
+    /// to the exact versions in it. Returns every package of the shrinkwrapped tree,
+    /// with their dependencies pinned the same way.
+    pub fn pin(&self, version: &mut NpmPackageVersion) -> Vec<NpmPackageVersion> {
+        self.pin_dependencies(version, "");
+
+        let mut pinned = vec![];
+        for (path, package) in &self.packages {
+            if path.is_empty() || package.dev || package.link {
+                continue;
+            }
+
+            let (Some(package_version), Some(resolved)) = (&package.version, &package.resolved)
+            else {
+                continue;
+            };
+
+            let name = match (&package.name, path.rsplit_once("node_modules/")) {
+                (Some(name), _) => name.to_owned(),
+                (None, Some((_, name))) => name.to_string(),
+                (None, None) => continue,
+            };
+
+            let mut version = NpmPackageVersion {
+                name,
+                version: package_version.to_owned(),
+                dependencies: package.dependencies.clone(),
+                optional_dependencies: BTreeMap::new(),
+                dist: NpmVersionDist {
+                    shasum: String::new(),
+                    tarball: resolved.to_owned(),
+                    integrity: package.integrity.to_owned(),
+                    file_count: None,
+                    unpacked_size: None,
+                    npm_signatures: None,
+                    signatures: None,
+                },
+                engines: None,
+                deprecated: None,
+                has_shrinkwrap: false,
+            };
+            self.pin_dependencies(&mut version, path);
+            pinned.push(version);
+        }
+
+        pinned
+    }
+
+    fn pin_dependencies(&self, version: &mut NpmPackageVersion, path: &str) {
+        let dependencies = version
+            .dependencies
+            .iter_mut()
+            .chain(version.optional_dependencies.iter_mut());
+
+        for (name, range) in dependencies {
+            if let Some(pinned) = self.find_dependency(path, name) {
+                *range = VersionRangeSpecifier::new(pinned.to_string());
+            }
+        }
+    }
+
+    /// Find the version `name` resolves to from the package installed at `path`,
+    /// looking in every `node_modules` folder up to the root like node does.
+    fn find_dependency(&self, path: &str, name: &str) -> Option<&Version> {
+        let mut base = path;
+        loop {
+            let candidate = if base.is_empty() {
+                format!("node_modules/{name}")
+            } else {
+                format!("{base}/node_modules/{name}")
+            };
+
+            if let Some(version) = self
+                .packages
+                .get(&candidate)
+                .and_then(|package| package.version.as_ref())
+            {
+                return Some(version);
+            }
+
+            if base.is_empty() {
+                return None;
+            }
+
+            base = match base.rfind("/node_modules/") {
+                Some(index) => &base[..index],
+                None => "",
+            };
+        }
+    }
+}
+
+/// Read the shrinkwrap published with `version`, downloading the package to the store first.
+/// This downloads and extracts the whole package while resolving, since the shrinkwrap is only
+/// published inside the tarball. The extraction is kept in the store, so installing the package
+/// afterwards reuses it instead of downloading it again.
+pub async fn fetch_shrinkwrap(
+    version: &NpmPackageVersion,
+    config: &Config,
+) -> anyhow::Result<Option<Shrinkwrap>> {
+    let store_path = get_store_package_path(&version.name, &version.version);
+    if !store_path.exists() {
+        download_package_to_store(
+            version.name.clone(),
+            version.version.clone(),
+            version.dist.tarball.clone(),
+            version.dist.integrity.clone(),
+            config,
+        )
+        .await?;
+    }
+
+    match fs::read(store_path.join(SHRINKWRAP_NAME)).await {
+        Ok(content) => Ok(Some(serde_json::from_slice(&content)?)),
+        Err(error) if error.kind() == ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(error.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn shrinkwrap() -> Shrinkwrap {
+        serde_json::from_value(json!({
+            "lockfileVersion": 3,
+            "packages": {
+                "": {
+                    "name": "cli",
+                    "version": "1.0.0",
+                    "dependencies": { "a": "^1.0.0", "b": "^1.0.0" }
+                },
+                "node_modules/a": {
+                    "version": "1.0.0",
+                    "resolved": "https://registry.npmjs.org/a/-/a-1.0.0.tgz",
+                    "dependencies": { "b": "^2.0.0" }
+                },
+                "node_modules/a/node_modules/b": {
+                    "version": "2.0.1",
+                    "resolved": "https://registry.npmjs.org/b/-/b-2.0.1.tgz"
+                },
+                "node_modules/b": {
+                    "version": "1.4.0",
+                    "resolved": "https://registry.npmjs.org/b/-/b-1.4.0.tgz"
+                },
+                "node_modules/typescript": {
+                    "version": "5.0.0",
+                    "resolved": "https://registry.npmjs.org/typescript/-/typescript-5.0.0.tgz",
+                    "dev": true
+                }
+            }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn pins_nested_dependencies() {
+        let mut cli: NpmPackageVersion = serde_json::from_value(json!({
+            "name": "cli",
+            "version": "1.0.0",
+            "dependencies": { "a": "^1.0.0", "b": "^1.0.0", "c": "^1.0.0" },
+            "dist": { "shasum": "", "tarball": "https://registry.npmjs.org/cli/-/cli-1.0.0.tgz" }
+        }))
+        .unwrap();
+
+        let pinned = shrinkwrap().pin(&mut cli);
+        let range = |range: &str| VersionRangeSpecifier::new(range.to_string());
+
+        assert_eq!(
+            cli.dependencies,
+            BTreeMap::from([
+                (String::from("a"), range("1.0.0")),
+                (String::from("b"), range("1.4.0")),
+                (String::from("c"), range("^1.0.0")),
+            ])
+        );
+        assert_eq!(
+            pinned
+                .iter()
+                .map(|version| format!("{}@{}", version.name, version.version))
+                .collect::<Vec<_>>(),
+            vec!["a@1.0.0", "b@2.0.1", "b@1.4.0"]
+        );
+        assert_eq!(
+            pinned[0].dependencies,
+            BTreeMap::from([(String::from("b"), range("2.0.1"))])
+        );
+    }
+}